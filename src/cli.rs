@@ -0,0 +1,198 @@
+use crate::error::{render_diagnostic, ParseErrorKind};
+use crate::eval::{self, Interpreter};
+use crate::lexer::{Token, Tokenizer};
+use crate::opt;
+use crate::parser::{default_operators, parse_source, parse_source_with_operators, Program};
+use crate::repl::Repl;
+use crate::sema;
+use std::io::{self, BufRead, Write};
+
+pub const EXIT_SUCCESS: i32 = 0;
+/// The input file could not be read.
+pub const EXIT_IO: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_LEXICAL: i32 = 3;
+pub const EXIT_SYNTAX: i32 = 4;
+pub const EXIT_RUNTIME: i32 = 5;
+/// Undefined names, arity mismatches and the like, found before running.
+pub const EXIT_SEMANTIC: i32 = 6;
+
+const USAGE: &str = "\
+usage: kaleidoscope [repl]
+       kaleidoscope lex FILE     print the token stream
+       kaleidoscope parse FILE   print the AST
+       kaleidoscope opt FILE     print the program after constant folding
+       kaleidoscope run FILE     run the program, printing top-level values
+
+FILE may be `-` to read from standard input.
+exit status: 0 success, 1 I/O error, 2 usage error, 3 lexical error,
+             4 syntax error, 5 runtime error, 6 semantic error
+";
+
+/// Entry point of the `kaleidoscope` binary. `args` excludes the program
+/// name; the return value is the process exit status.
+///
+/// `run` and the REPL evaluate on a thread with a large stack, see
+/// `eval::with_large_stack`, so that programs can recurse deeply.
+pub fn run<R, O, E>(args: &[String], stdin: R, mut stdout: O, mut stderr: E) -> i32
+where
+    R: BufRead + Send,
+    O: Write + Send,
+    E: Write + Send,
+{
+    match dispatch(args, stdin, &mut stdout, &mut stderr) {
+        Ok(status) => status,
+        Err(err) => {
+            let _ = writeln!(stderr, "error: {err}");
+            EXIT_IO
+        },
+    }
+}
+
+fn dispatch<R, O, E>(args: &[String], mut stdin: R, stdout: &mut O, stderr: &mut E) -> io::Result<i32>
+where
+    R: BufRead + Send,
+    O: Write + Send,
+    E: Write + Send,
+{
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (command, path) = match args.as_slice() {
+        [] | ["repl"] => {
+            eval::with_large_stack(|max_call_depth| Repl::new(stdout).with_max_call_depth(max_call_depth).run(stdin))?;
+            return Ok(EXIT_SUCCESS);
+        },
+        ["-h"] | ["--help"] | ["help"] => {
+            write!(stdout, "{USAGE}")?;
+            return Ok(EXIT_SUCCESS);
+        },
+        [command @ ("lex" | "parse" | "opt" | "run"), path] => (*command, *path),
+        _ => {
+            write!(stderr, "{USAGE}")?;
+            return Ok(EXIT_USAGE);
+        },
+    };
+
+    let source = if path == "-" {
+        let mut source = Vec::new();
+        stdin.read_to_end(&mut source)?;
+        source
+    } else {
+        match std::fs::read(path) {
+            Ok(source) => source,
+            Err(err) => {
+                writeln!(stderr, "error: cannot read '{path}': {err}")?;
+                return Ok(EXIT_IO);
+            },
+        }
+    };
+
+    match command {
+        "lex" => lex(&source, stdout, stderr),
+        "parse" => parse(&source, stdout, stderr),
+        "opt" => optimize(&source, stdout, stderr),
+        _ => eval::with_large_stack(|max_call_depth| run_program(&source, max_call_depth, stdout, stderr)),
+    }
+}
+
+fn lex(source: &[u8], stdout: &mut impl Write, stderr: &mut impl Write) -> io::Result<i32> {
+    let text = String::from_utf8_lossy(source);
+    let mut input = source;
+    let mut lexer = Tokenizer::new(&mut input);
+    let mut status = EXIT_SUCCESS;
+    loop {
+        let token = lexer.last_token();
+        let span = lexer.last_span();
+        writeln!(stdout, "{}:{}\t{:?}", span.start.line, span.start.column, token)?;
+        match token {
+            Token::Eof => return Ok(status),
+            Token::Error { error } => {
+                write!(stderr, "{}", render_diagnostic("error", &error.to_string(), span, &text))?;
+                status = EXIT_LEXICAL;
+            },
+            _ => {},
+        }
+        lexer.next();
+    }
+}
+
+fn parse(source: &[u8], stdout: &mut impl Write, stderr: &mut impl Write) -> io::Result<i32> {
+    let program = parse_source(source);
+    for item in &program.items {
+        writeln!(stdout, "{item:#?}")?;
+    }
+    report_parse_errors(&program, source, stderr)
+}
+
+/// Prints each rewrite as a comment, followed by the optimized program,
+/// which runs just like the original. Every item ends in `;` so that one
+/// starting with `(` or `-` can't be read as part of the item before it.
+fn optimize(source: &[u8], stdout: &mut impl Write, stderr: &mut impl Write) -> io::Result<i32> {
+    let mut prec = default_operators();
+    let mut program = parse_source_with_operators(source, &mut prec);
+    let status = report_parse_errors(&program, source, stderr)?;
+    if status != EXIT_SUCCESS {
+        return Ok(status);
+    }
+
+    for rewrite in opt::optimize(&mut program) {
+        writeln!(stdout, "# {rewrite}")?;
+    }
+    for item in &program.items {
+        writeln!(stdout, "{};", item.display_with(&prec))?;
+    }
+    Ok(EXIT_SUCCESS)
+}
+
+fn run_program(source: &[u8], max_call_depth: usize, stdout: &mut impl Write, stderr: &mut impl Write) -> io::Result<i32> {
+    let mut program = parse_source(source);
+    let status = report_parse_errors(&program, source, stderr)?;
+    if status != EXIT_SUCCESS {
+        return Ok(status);
+    }
+
+    let diagnostics = sema::analyze(&program);
+    let text = String::from_utf8_lossy(source);
+    for diagnostic in &diagnostics {
+        write!(stderr, "{}", diagnostic.render(&text))?;
+    }
+    if sema::has_errors(&diagnostics) {
+        return Ok(EXIT_SEMANTIC);
+    }
+    opt::optimize(&mut program);
+
+    let mut interpreter = Interpreter::new().with_max_call_depth(max_call_depth);
+    let exprs = match interpreter.declare(program) {
+        Ok(exprs) => exprs,
+        Err(err) => {
+            writeln!(stderr, "error: {err}")?;
+            return Ok(EXIT_RUNTIME);
+        },
+    };
+    for expr in exprs {
+        match interpreter.eval(&expr) {
+            Ok(value) => writeln!(stdout, "{value}")?,
+            Err(err) => {
+                writeln!(stderr, "error: {err}")?;
+                return Ok(EXIT_RUNTIME);
+            },
+        }
+    }
+    Ok(EXIT_SUCCESS)
+}
+
+/// Renders every parse error and picks the matching exit status; lexical
+/// errors win over syntax errors since they are usually the root cause.
+fn report_parse_errors(program: &Program, source: &[u8], stderr: &mut impl Write) -> io::Result<i32> {
+    let text = String::from_utf8_lossy(source);
+    for err in &program.errors {
+        write!(stderr, "{}", err.render(&text))?;
+    }
+
+    if program.errors.iter().any(|err| err.kind == ParseErrorKind::Lexical) {
+        Ok(EXIT_LEXICAL)
+    } else if !program.is_ok() {
+        Ok(EXIT_SYNTAX)
+    } else {
+        Ok(EXIT_SUCCESS)
+    }
+}
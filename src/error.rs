@@ -0,0 +1,116 @@
+use crate::lexer::{Span, Token};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    /// The input ended in the middle of a construct.
+    UnexpectedEof,
+    /// A token other than the expected one was found.
+    UnexpectedToken,
+    /// The lexer could not make a token out of the input.
+    Lexical,
+    /// An operator definition with the wrong number of operands, a bad
+    /// precedence or redefining a built-in operator.
+    InvalidOperator,
+    /// Non-associative operators of the same precedence chained without
+    /// parentheses, e.g. `a < b < c`.
+    NonAssociative,
+    /// The left-hand side of `=` is not a variable.
+    InvalidAssignment,
+    /// A prototype lists the same parameter name twice.
+    DuplicateParameter,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub message: String,
+    /// Human readable descriptions of the tokens that would have been accepted.
+    pub expected: Vec<String>,
+    pub found: Token,
+    pub span: Span,
+}
+
+impl ParseError {
+    /// Creates an error for `found` not matching what the parser expected.
+    /// Lexical errors take precedence over the parser's complaint, since
+    /// that's what the user needs to fix.
+    pub fn new(message: &str, expected: &[&str], found: Token, span: Span) -> Self {
+        if let Token::Error { error } = &found {
+            return Self {
+                kind: ParseErrorKind::Lexical,
+                message: error.to_string(),
+                expected: Vec::new(),
+                found,
+                span,
+            };
+        }
+
+        let kind = match found {
+            Token::Eof => ParseErrorKind::UnexpectedEof,
+            _ => ParseErrorKind::UnexpectedToken,
+        };
+        Self {
+            kind,
+            message: message.to_string(),
+            expected: expected.iter().map(|s| s.to_string()).collect(),
+            found,
+            span,
+        }
+    }
+
+    /// Creates an error of a specific `kind` that isn't about an unexpected token.
+    pub fn with_kind(kind: ParseErrorKind, message: &str, found: Token, span: Span) -> Self {
+        Self { kind, message: message.to_string(), expected: Vec::new(), found, span }
+    }
+
+    /// Formats the error together with the offending line of `source`
+    /// and a caret underline below the erroneous token.
+    pub fn render(&self, source: &str) -> String {
+        render_diagnostic("error", &self.to_string(), self.span, source)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if !self.expected.is_empty() {
+            write!(f, " (expected {}, found {})", self.expected.join(" or "), self.found)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Renders `message` in the style of
+///
+/// ```text
+/// error: Expected ')'
+///  --> 1:7
+///   |
+/// 1 | (1 + 2
+///   |       ^
+/// ```
+pub fn render_diagnostic(severity: &str, message: &str, span: Span, source: &str) -> String {
+    let line_no = span.start.line.max(1);
+    let line = source.lines().nth(line_no - 1).unwrap_or("");
+    let gutter = " ".repeat(line_no.to_string().len());
+
+    let line_len = line.chars().count();
+    let start_col = span.start.column.max(1) - 1;
+    let end_col = if span.end.line == span.start.line {
+        span.end.column.max(1) - 1
+    } else {
+        line_len
+    };
+    let width = end_col.saturating_sub(start_col).max(1);
+
+    let mut out = String::new();
+    out.push_str(&format!("{severity}: {message}\n"));
+    out.push_str(&format!("{gutter}--> {}:{}\n", line_no, start_col + 1));
+    out.push_str(&format!("{gutter} |\n"));
+    out.push_str(&format!("{line_no} | {line}\n"));
+    out.push_str(&format!("{gutter} | {}{}\n", " ".repeat(start_col), "^".repeat(width)));
+    out
+}
@@ -0,0 +1,370 @@
+use crate::parser::{ExprAST, BINARY_PREFIX, UNARY_PREFIX, FunctionAST, Item, Program, PrototypeAST, VarBinding};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Calls nested deeper than this are reported instead of overflowing the
+/// stack; the limit leaves room for deep expressions on a 2 MiB thread stack.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 500;
+
+/// Call depth limit for interpreters running inside `with_large_stack`.
+pub const LARGE_STACK_MAX_CALL_DEPTH: usize = 10_000;
+
+/// Stack size of the thread started by `with_large_stack`, about 100 MiB:
+/// a call takes up to about 10 KiB of stack in a debug build.
+pub const LARGE_STACK_SIZE: usize = LARGE_STACK_MAX_CALL_DEPTH * (10 << 10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    UnknownFunction(String),
+    /// An `extern` for which the interpreter has no native implementation.
+    UnknownExtern(String),
+    ArityMismatch { name: String, expected: usize, found: usize },
+    UnboundVariable(String),
+    UnknownOperator(String),
+    StackOverflow { name: String, limit: usize },
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::UnknownFunction(name) => write!(f, "Unknown function '{name}'"),
+            RuntimeError::UnknownExtern(name) => write!(f, "No native implementation for extern '{name}'"),
+            RuntimeError::ArityMismatch { name, expected, found } => {
+                write!(f, "Function '{name}' takes {expected} argument(s) but {found} were supplied")
+            },
+            RuntimeError::UnboundVariable(name) => write!(f, "Unbound variable '{name}'"),
+            RuntimeError::UnknownOperator(op) => write!(f, "Unknown operator '{op}'"),
+            RuntimeError::StackOverflow { name, limit } => {
+                write!(f, "Maximum call depth of {limit} exceeded in '{name}'")
+            },
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+pub type EvalResult<T> = Result<T, RuntimeError>;
+
+enum Function {
+    Native { arity: usize, func: fn(&[f64]) -> f64 },
+    User { args: Vec<String>, body: Box<ExprAST> },
+}
+
+impl Function {
+    fn arity(&self) -> usize {
+        match self {
+            Function::Native { arity, .. } => *arity,
+            Function::User { args, .. } => args.len(),
+        }
+    }
+}
+
+/// Functions that can be made available with `extern`.
+fn native_function(name: &str) -> Option<Function> {
+    let (arity, func): (usize, fn(&[f64]) -> f64) = match name {
+        "sin" => (1, |args| args[0].sin()),
+        "cos" => (1, |args| args[0].cos()),
+        "tan" => (1, |args| args[0].tan()),
+        "sqrt" => (1, |args| args[0].sqrt()),
+        "exp" => (1, |args| args[0].exp()),
+        "log" => (1, |args| args[0].ln()),
+        "fabs" => (1, |args| args[0].abs()),
+        "pow" => (2, |args| args[0].powf(args[1])),
+        "putchard" => (1, |args| {
+            eprint!("{}", char::from_u32(args[0] as u32).unwrap_or(char::REPLACEMENT_CHARACTER));
+            0.0
+        }),
+        "printd" => (1, |args| {
+            eprintln!("{}", args[0]);
+            0.0
+        }),
+        _ => return None,
+    };
+    Some(Function::Native { arity, func })
+}
+
+/// The number of arguments of the native function an `extern` named `name`
+/// refers to, or `None` if there is no such function.
+pub fn native_arity(name: &str) -> Option<usize> {
+    native_function(name).map(|native| native.arity())
+}
+
+/// Any non-zero value (including NaN) counts as true.
+pub fn is_truthy(value: f64) -> bool {
+    value != 0.0
+}
+
+pub fn from_bool(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+/// Applies a built-in binary operator, or returns `None` for a
+/// user-defined one.
+pub fn builtin_binary(op: &str, lhs: f64, rhs: f64) -> Option<f64> {
+    Some(match op {
+        "<" => from_bool(lhs < rhs),
+        ">" => from_bool(lhs > rhs),
+        "<=" => from_bool(lhs <= rhs),
+        ">=" => from_bool(lhs >= rhs),
+        "==" => from_bool(lhs == rhs),
+        "!=" => from_bool(lhs != rhs),
+        "+" => lhs + rhs,
+        "-" => lhs - rhs,
+        "*" => lhs * rhs,
+        "/" => lhs / rhs,
+        "%" => lhs % rhs,
+        "^" => lhs.powf(rhs),
+        _ => return None,
+    })
+}
+
+/// Tree-walking interpreter over the parser's AST.
+///
+/// Definitions and externs are collected into a function table as they are
+/// evaluated; top-level expressions are evaluated immediately.
+pub struct Interpreter {
+    functions: HashMap<String, Function>,
+    depth: Cell<usize>,
+    max_call_depth: usize,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self {
+            functions: HashMap::new(),
+            depth: Cell::new(0),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+        }
+    }
+
+    /// Allows calls to nest `max_call_depth` deep. The thread evaluating
+    /// must have the stack for it, see `with_large_stack`.
+    pub fn with_max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.max_call_depth = max_call_depth;
+        self
+    }
+
+    /// Registers the definitions and externs of `program`, then evaluates
+    /// its top-level expressions in order and returns their values.
+    pub fn run(&mut self, program: Program) -> EvalResult<Vec<f64>> {
+        self.declare(program)?.iter().map(|expr| self.eval(expr)).collect()
+    }
+
+    /// Registers every definition and extern of `program` up front, so that
+    /// a top-level expression can call any function of the program, also
+    /// one defined below it. Returns the top-level expressions, in order,
+    /// for `eval`.
+    pub fn declare(&mut self, program: Program) -> EvalResult<Vec<Box<ExprAST>>> {
+        let mut exprs = Vec::new();
+        for item in program.items {
+            match item {
+                Item::TopLevelExpr(function) => exprs.push(function.body),
+                item => {
+                    self.eval_item(item)?;
+                },
+            }
+        }
+        Ok(exprs)
+    }
+
+    /// Registers a definition or extern, or evaluates a top-level
+    /// expression and returns its value.
+    pub fn eval_item(&mut self, item: Item) -> EvalResult<Option<f64>> {
+        match item {
+            Item::Extern(PrototypeAST { name, args, .. }) => {
+                let Some(native) = native_function(&name) else {
+                    return Err(RuntimeError::UnknownExtern(name));
+                };
+                if native.arity() != args.len() {
+                    return Err(RuntimeError::ArityMismatch { name, expected: native.arity(), found: args.len() });
+                }
+                self.functions.insert(name, native);
+                Ok(None)
+            },
+            Item::Definition(FunctionAST { proto, body }) => {
+                self.functions.insert(proto.name, Function::User { args: proto.args, body });
+                Ok(None)
+            },
+            Item::TopLevelExpr(function) => self.eval(&function.body).map(Some),
+        }
+    }
+
+    /// Evaluates a single expression with no variables in scope.
+    pub fn eval(&self, expr: &ExprAST) -> EvalResult<f64> {
+        self.eval_expr(expr, &mut Vec::new())
+    }
+
+    /// `scope` holds the visible variables, innermost binding last.
+    fn eval_expr(&self, expr: &ExprAST, scope: &mut Vec<(String, f64)>) -> EvalResult<f64> {
+        match expr {
+            ExprAST::NumberExprAST { val, .. } => Ok(*val),
+            ExprAST::VariableExprAST { name, .. } => scope.iter().rev()
+                .find(|(var, _)| var == name)
+                .map(|(_, value)| *value)
+                .ok_or_else(|| RuntimeError::UnboundVariable(name.clone())),
+            ExprAST::UnaryExprAST { op, operand, .. } => {
+                let operand = self.eval_expr(operand, scope)?;
+                match op.as_str() {
+                    "-" => Ok(-operand),
+                    _ => self.call_operator(UNARY_PREFIX, op, &[operand]),
+                }
+            },
+            ExprAST::BinaryExprAST { op, lhs, rhs, .. } => {
+                let lhs = self.eval_expr(lhs, scope)?;
+                let rhs = self.eval_expr(rhs, scope)?;
+                match builtin_binary(op, lhs, rhs) {
+                    Some(value) => Ok(value),
+                    None => self.call_operator(BINARY_PREFIX, op, &[lhs, rhs]),
+                }
+            },
+            ExprAST::LogicalExprAST { op, lhs, rhs, .. } => {
+                let lhs = is_truthy(self.eval_expr(lhs, scope)?);
+                match op.as_str() {
+                    "&&" if !lhs => Ok(0.0),
+                    "||" if lhs => Ok(1.0),
+                    "&&" | "||" => Ok(from_bool(is_truthy(self.eval_expr(rhs, scope)?))),
+                    _ => Err(RuntimeError::UnknownOperator(op.clone())),
+                }
+            },
+            ExprAST::CallExprAST { callee, args, .. } => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(self.eval_expr(arg, scope)?);
+                }
+                self.call(callee, &values)
+            },
+            ExprAST::IfExprAST { cond, then, else_, .. } => {
+                if is_truthy(self.eval_expr(cond, scope)?) {
+                    self.eval_expr(then, scope)
+                } else {
+                    self.eval_expr(else_, scope)
+                }
+            },
+            ExprAST::VarExprAST { vars, body, .. } => {
+                let outer = scope.len();
+                let result = self.eval_var(vars, body, scope);
+                scope.truncate(outer);
+                result
+            },
+            ExprAST::BlockExprAST { exprs, .. } => {
+                let mut value = 0.0;
+                for expr in exprs {
+                    value = self.eval_expr(expr, scope)?;
+                }
+                Ok(value)
+            },
+            ExprAST::AssignExprAST { name, value, .. } => {
+                let value = self.eval_expr(value, scope)?;
+                let Some(binding) = scope.iter_mut().rev().find(|(var, _)| var == name) else {
+                    return Err(RuntimeError::UnboundVariable(name.clone()));
+                };
+                binding.1 = value;
+                Ok(value)
+            },
+            ExprAST::ForExprAST { var, start, end, step, body, .. } => {
+                // Unlike the classic Kaleidoscope loop the end condition is
+                // checked before the body, so the body may run zero times.
+                let start = self.eval_expr(start, scope)?;
+                scope.push((var.clone(), start));
+                let result = self.eval_loop(end, step.as_deref(), body, scope);
+                scope.pop();
+                result
+            },
+        }
+    }
+
+    /// Binds the variables of a `var` expression one after the other, then
+    /// evaluates its body. The caller removes the bindings again.
+    fn eval_var(&self, vars: &[VarBinding], body: &ExprAST,
+                scope: &mut Vec<(String, f64)>) -> EvalResult<f64> {
+        for VarBinding { name, init, .. } in vars {
+            let value = match init {
+                Some(init) => self.eval_expr(init, scope)?,
+                None => 0.0,
+            };
+            scope.push((name.clone(), value));
+        }
+        self.eval_expr(body, scope)
+    }
+
+    /// Runs a `for` loop whose variable is the last entry of `scope`.
+    /// Loops always evaluate to 0.
+    fn eval_loop(&self, end: &ExprAST, step: Option<&ExprAST>, body: &ExprAST,
+                 scope: &mut Vec<(String, f64)>) -> EvalResult<f64> {
+        let var_idx = scope.len() - 1;
+        while is_truthy(self.eval_expr(end, scope)?) {
+            self.eval_expr(body, scope)?;
+            let step = match step {
+                Some(step) => self.eval_expr(step, scope)?,
+                None => 1.0,
+            };
+            scope[var_idx].1 += step;
+        }
+        Ok(0.0)
+    }
+
+    /// Calls a user-defined operator, which is stored as a function named
+    /// `prefix` followed by the operator.
+    fn call_operator(&self, prefix: &str, op: &str, args: &[f64]) -> EvalResult<f64> {
+        let name = format!("{prefix}{op}");
+        if !self.functions.contains_key(&name) {
+            return Err(RuntimeError::UnknownOperator(op.to_string()));
+        }
+        self.call(&name, args)
+    }
+
+    fn call(&self, callee: &str, args: &[f64]) -> EvalResult<f64> {
+        let Some(function) = self.functions.get(callee) else {
+            return Err(RuntimeError::UnknownFunction(callee.to_string()));
+        };
+        if function.arity() != args.len() {
+            return Err(RuntimeError::ArityMismatch {
+                name: callee.to_string(),
+                expected: function.arity(),
+                found: args.len(),
+            });
+        }
+
+        match function {
+            Function::Native { func, .. } => Ok(func(args)),
+            Function::User { args: params, body } => {
+                if self.depth.get() >= self.max_call_depth {
+                    return Err(RuntimeError::StackOverflow { name: callee.to_string(), limit: self.max_call_depth });
+                }
+                let mut scope: Vec<(String, f64)> = params.iter().cloned().zip(args.iter().copied()).collect();
+                self.depth.set(self.depth.get() + 1);
+                let result = self.eval_expr(body, &mut scope);
+                self.depth.set(self.depth.get() - 1);
+                result
+            },
+        }
+    }
+}
+
+/// Runs `f` on a new thread with a `LARGE_STACK_SIZE` stack, passing it
+/// the call depth limit the stack allows. Should the thread fail to start,
+/// `f` runs on the current thread with `DEFAULT_MAX_CALL_DEPTH` instead.
+pub fn with_large_stack<T: Send>(f: impl FnOnce(usize) -> T + Send) -> T {
+    // `spawn_scoped` drops its closure on failure, so `f` is kept here to
+    // be taken back for the fallback.
+    let f = std::sync::Mutex::new(Some(f));
+    let take = || f.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+    std::thread::scope(|scope| {
+        let spawned = std::thread::Builder::new()
+            .stack_size(LARGE_STACK_SIZE)
+            .spawn_scoped(scope, || take().map(|f| f(LARGE_STACK_MAX_CALL_DEPTH)));
+        match spawned {
+            Ok(handle) => handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+            Err(_) => None,
+        }
+    })
+    .or_else(|| take().map(|f| f(DEFAULT_MAX_CALL_DEPTH)))
+    .expect("the closure runs exactly once")
+}
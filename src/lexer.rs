@@ -1,43 +1,158 @@
+use crate::trace::{TraceEvent, TraceSink};
+use unicode_xid::UnicodeXID;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    /// The input contains a byte sequence that is not valid UTF-8.
+    InvalidUtf8,
+    /// Reading from the underlying input failed.
+    Io(String),
+    /// A numeric literal such as `1.2.3`, `0x` or `1__0`.
+    MalformedNumber(String),
+}
+
+impl std::fmt::Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LexError::InvalidUtf8 => write!(f, "Invalid UTF-8 in input"),
+            LexError::Io(message) => write!(f, "Failed to read input: {message}"),
+            LexError::MalformedNumber(literal) => write!(f, "Malformed number literal '{literal}'"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token { 
     Eof, 
     Def, 
     Extern,
+    If,
+    Then,
+    Else,
+    For,
+    In,
+    Var,
+    Unary,
+    Binary,
     Identifier { id : String },
     Number { value : f64 },
+    LParen,
+    RParen,
+    Comma,
+    Semicolon,
+    LBrace,
+    RBrace,
+    Operator { op : String },
+    Error { error : LexError },
 }
 
-pub fn is_identifier(tok: &Token) -> bool {
-    match tok { 
-        Token::Identifier {..} => true,
-        _ => false
+/// Operators spelled with two characters; everything else is a single character.
+const MULTI_CHAR_OPERATORS: [&str; 7] = ["<=", ">=", "==", "!=", "->", "&&", "||"];
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Eof => write!(f, "end of input"),
+            Token::Def => write!(f, "'def'"),
+            Token::Extern => write!(f, "'extern'"),
+            Token::If => write!(f, "'if'"),
+            Token::Then => write!(f, "'then'"),
+            Token::Else => write!(f, "'else'"),
+            Token::For => write!(f, "'for'"),
+            Token::In => write!(f, "'in'"),
+            Token::Var => write!(f, "'var'"),
+            Token::Unary => write!(f, "'unary'"),
+            Token::Binary => write!(f, "'binary'"),
+            Token::Identifier { id } => write!(f, "'{id}'"),
+            Token::Number { value } => write!(f, "number {value}"),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::Comma => write!(f, "','"),
+            Token::Semicolon => write!(f, "';'"),
+            Token::LBrace => write!(f, "'{{'"),
+            Token::RBrace => write!(f, "'}}'"),
+            Token::Operator { op } => write!(f, "operator '{op}'"),
+            Token::Error { error } => write!(f, "{error}"),
+        }
     }
 }
 
-pub fn get_id(tok: &Token) -> String {
-    match tok {
-        Token::Identifier{id} => id.to_string(),
-        _ => unreachable!()
+/// A point in the source: byte offset plus 1-based line and column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    fn start() -> Self {
+        Self { offset: 0, line: 1, column: 1 }
+    }
+}
+
+/// Source range `[start, end)` covered by a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    pub fn new(start: Position, end: Position) -> Self {
+        Self { start, end }
+    }
+
+    /// Smallest span covering both `self` and `other`.
+    pub fn to(&self, other: Span) -> Span {
+        Span { start: self.start, end: other.end }
     }
 }
 
 pub struct Tokenizer<'a> {
     input: &'a mut dyn std::io::Read,
     last_char: char,
+    /// Set when `last_char` stands in for an invalid UTF-8 sequence.
+    invalid_char: bool,
+    /// Byte read ahead while decoding a truncated UTF-8 sequence.
+    pending_byte: Option<u8>,
+    io_error: Option<String>,
     last_token: Token, 
+    last_span: Span,
     eof_reached: bool,
+    /// Position of `last_char` in the input.
+    char_pos: Position,
+    /// Position right after `last_char`, i.e. of the next character to be read.
+    next_pos: Position,
+    trace: Option<Box<dyn TraceSink>>,
 }
 
 impl<'a> Tokenizer<'a> {
     pub fn new(input: &'a mut impl std::io::Read) -> Self {
+        Self::with_trace(input, None)
+    }
+
+    /// Like `new`, but reports every character and token to `sink`.
+    pub fn with_trace_sink(input: &'a mut impl std::io::Read, sink: Box<dyn TraceSink>) -> Self {
+        Self::with_trace(input, Some(sink))
+    }
+
+    fn with_trace(input: &'a mut impl std::io::Read, trace: Option<Box<dyn TraceSink>>) -> Self {
         let mut result = Self {
-            input: input,
+            input,
             last_char: ' ',
+            invalid_char: false,
+            pending_byte: None,
+            io_error: None,
             last_token: Token::Eof,
-            eof_reached: false
+            last_span: Span::default(),
+            eof_reached: false,
+            char_pos: Position::start(),
+            next_pos: Position::start(),
+            trace,
         };
         result.next();
-        return result;
+        result
     }
 
     pub fn next(&mut self) {
@@ -45,85 +160,298 @@ impl<'a> Tokenizer<'a> {
     }
     
     pub fn next_(&mut self) {
-        if self.eof_reached {
-            self.last_token = Token::Eof;
-            return;
-        }
-
-        while self.last_char == ' ' || self.last_char == '\n' || self.last_char == '\t' {
-            if !self.next_char() {
-                if self.eof_reached {
-                    self.last_token = Token::Eof;
-                    return;
-                } else {
-                    println!("!!!! Unknown error");
-                    assert_eq!(true, false);
+        self.skip_whitespace_and_comments();
+        let start = self.char_pos;
+        self.last_token = self.lex_token();
+        self.last_span = Span::new(start, self.char_pos);
+        if let Some(sink) = &mut self.trace {
+            sink.event(&TraceEvent::Token { token: self.last_token.clone(), span: self.last_span });
+        }
+    }
+
+    fn skip_whitespace_and_comments(&mut self) {
+        while !self.eof_reached {
+            if self.last_char.is_whitespace() {
+                self.next_char();
+            } else if self.last_char == '#' {
+                while self.next_char() && self.last_char != '\n' {
                 }
+            } else {
+                break;
             }
         }
-    
-        if self.last_char.is_alphabetic() {
+    }
+
+    fn lex_token(&mut self) -> Token {
+        if let Some(message) = self.io_error.take() {
+            return Token::Error { error: LexError::Io(message) };
+        }
+
+        if self.eof_reached {
+            return Token::Eof;
+        }
+
+        if self.invalid_char {
+            self.next_char();
+            return Token::Error { error: LexError::InvalidUtf8 };
+        }
+
+        if is_identifier_start(self.last_char) {
             let mut identifier: String = self.last_char.to_string();
             
-            while self.next_char() && self.last_char.is_alphanumeric() {
-                println!("last_char: {}", self.last_char);
-                identifier.push(self.last_char as char);
-                
-            }
-    
-            if identifier == "def" {
-                self.last_token = Token::Def;
-                return;
+            while self.next_char() && self.last_char.is_xid_continue() {
+                identifier.push(self.last_char);
             }
     
-            if identifier == "extern" {
-                self.last_token = Token::Extern;
-                return;
+            match identifier.as_str() {
+                "def" => Token::Def,
+                "extern" => Token::Extern,
+                "if" => Token::If,
+                "then" => Token::Then,
+                "else" => Token::Else,
+                "for" => Token::For,
+                "in" => Token::In,
+                "var" => Token::Var,
+                "unary" => Token::Unary,
+                "binary" => Token::Binary,
+                _ => Token::Identifier { id: identifier },
             }
-
-            self.last_token = Token::Identifier { id: identifier };
-        } else if self.last_char.is_digit(10) {
-            let mut num_str = String::new();
-            num_str.push(self.last_char);
-            while self.next_char() && (self.last_char.is_digit(10) || self.last_char == '.') {
-                num_str.push(self.last_char);
+        } else if self.last_char.is_ascii_digit() {
+            self.lex_number(String::new())
+        } else if self.last_char == '.' {
+            // either a number like `.5` or a lone '.' operator
+            if self.next_char() && self.last_char.is_ascii_digit() {
+                return self.lex_number(String::from("."));
             }
-            let num: f64 = num_str.parse().unwrap();
-            self.last_token = Token::Number {value: num};
-        } else if self.last_char == '#' {
-            while self.next_char() && self.last_char != '\n' {    
+            Token::Operator { op: String::from(".") }
+        } else if self.last_char == '(' {
+            self.next_char();
+            Token::LParen
+        } else if self.last_char == ')' {
+            self.next_char();
+            Token::RParen
+        } else if self.last_char == ',' {
+            self.next_char();
+            Token::Comma
+        } else if self.last_char == ';' {
+            self.next_char();
+            Token::Semicolon
+        } else if self.last_char == '{' {
+            self.next_char();
+            Token::LBrace
+        } else if self.last_char == '}' {
+            self.next_char();
+            Token::RBrace
+        } else {
+            // operator such as '+', '<' or '<='
+            let mut op = String::from(self.last_char);
+            if self.next_char() {
+                let mut candidate = op.clone();
+                candidate.push(self.last_char);
+                if MULTI_CHAR_OPERATORS.contains(&candidate.as_str()) {
+                    op = candidate;
+                    self.next_char();
+                }
             }
-            if !self.eof_reached {
-                self.next_char(); // skip current '\n'
-                self.next();
+            Token::Operator { op }
+        }
+    }
+
+    /// number
+    ///     ::= decimal | '0x' hexdigits | '0o' octdigits | '0b' bindigits
+    /// decimal
+    ///     ::= digits ('.' digits?)? exponent? | '.' digits exponent?
+    /// exponent
+    ///     ::= ('e' | 'E') ('+' | '-')? digits
+    ///
+    /// where digits may be separated by single underscores, as in `1_000`.
+    /// The whole run of characters that looks like a literal is consumed
+    /// before it is validated, so a malformed literal becomes a single
+    /// error token and lexing carries on after it.
+    fn lex_number(&mut self, mut literal: String) -> Token {
+        literal.push(self.last_char);
+        while self.next_char() {
+            let ch = self.last_char;
+            let exponent_sign = (ch == '+' || ch == '-')
+                && radix_prefix(&literal).is_none()
+                && literal.ends_with(['e', 'E']);
+            if !(ch.is_ascii_alphanumeric() || ch == '_' || ch == '.' || exponent_sign) {
+                break;
             }
-        } else if self.eof_reached {
-            // is EOF, return tok_eof
-            self.last_token = Token::Eof;
-        } else {
-            // should be one of '+', '-', '*', '(', ')'
-            let ch = String::from(self.last_char);
-            self.next_char();
-            self.last_token = Token::Identifier { id: ch };
+            literal.push(ch);
+        }
+
+        match parse_number(&literal) {
+            Some(value) => Token::Number { value },
+            None => Token::Error { error: LexError::MalformedNumber(literal) },
         }
     }
 
     fn next_char(&mut self) -> bool {
-        let res = self.next_char_();
-        res
+        self.next_char_()
     }
 
     fn next_char_(&mut self) -> bool {
-        let mut ch: &mut [u8] = &mut [0];
-        match self.input.read(&mut ch) {
-            Ok(0) => { self.eof_reached = true; return false },
-            Ok(idx) => { println!("read: {}, char: {}", idx, ch[0]); self.last_char = ch[0] as char; return true },
-            Err(_) => return false,
+        let Some(first) = self.read_byte() else {
+            self.eof_reached = true;
+            self.char_pos = self.next_pos;
+            return false;
+        };
+
+        let width = utf8_width(first);
+        let mut bytes = [first, 0, 0, 0];
+        let mut len = 1;
+        while len < width {
+            match self.read_byte() {
+                Some(byte) if byte & 0xC0 == 0x80 => {
+                    bytes[len] = byte;
+                    len += 1;
+                },
+                Some(byte) => {
+                    // not a continuation byte, leave it for the next character
+                    self.pending_byte = Some(byte);
+                    break;
+                },
+                None => break,
+            }
+        }
+
+        match std::str::from_utf8(&bytes[..len]) {
+            Ok(decoded) if len == width => {
+                self.last_char = decoded.chars().next().unwrap();
+                self.invalid_char = false;
+            },
+            _ => {
+                self.last_char = char::REPLACEMENT_CHARACTER;
+                self.invalid_char = true;
+            },
+        }
+        self.char_pos = self.next_pos;
+        if let Some(sink) = &mut self.trace {
+            sink.event(&TraceEvent::CharRead { ch: self.last_char, position: self.char_pos });
+        }
+        self.next_pos.offset += len;
+        if self.last_char == '\n' {
+            self.next_pos.line += 1;
+            self.next_pos.column = 1;
+        } else {
+            self.next_pos.column += 1;
+        }
+        true
+    }
+
+    /// Reads a single byte, returning `None` at the end of input or after
+    /// a read error (which is remembered and reported as an error token).
+    fn read_byte(&mut self) -> Option<u8> {
+        if let Some(byte) = self.pending_byte.take() {
+            return Some(byte);
+        }
+        if self.eof_reached {
+            return None;
+        }
+
+        let mut buf = [0u8; 1];
+        loop {
+            match self.input.read(&mut buf) {
+                Ok(0) => return None,
+                Ok(_) => return Some(buf[0]),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    self.io_error = Some(err.to_string());
+                    self.eof_reached = true;
+                    return None;
+                },
+            }
         }
     }
 
     pub fn last_token(&self) -> Token {
         self.last_token.clone()
     }
+
+    /// Source span of the token returned by `last_token`.
+    pub fn last_span(&self) -> Span {
+        self.last_span
+    }
 }
 
+/// Identifiers follow UAX #31: an XID_Start character (or '_') followed by
+/// XID_Continue characters.
+fn is_identifier_start(ch: char) -> bool {
+    ch == '_' || ch.is_xid_start()
+}
+
+/// Length of the UTF-8 sequence introduced by `first`, or 0 if `first`
+/// cannot start a sequence.
+fn utf8_width(first: u8) -> usize {
+    match first {
+        0x00..=0x7F => 1,
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => 0,
+    }
+}
+
+fn radix_prefix(literal: &str) -> Option<u32> {
+    match literal.get(..2) {
+        Some("0x") | Some("0X") => Some(16),
+        Some("0o") | Some("0O") => Some(8),
+        Some("0b") | Some("0B") => Some(2),
+        _ => None,
+    }
+}
+
+fn parse_number(literal: &str) -> Option<f64> {
+    let Some(radix) = radix_prefix(literal) else {
+        return parse_decimal(literal);
+    };
+
+    let digits = &literal[2..];
+    if digits.is_empty() || !separators_ok(digits, radix) {
+        return None;
+    }
+
+    let mut value = 0_f64;
+    for ch in digits.chars().filter(|&ch| ch != '_') {
+        value = value * radix as f64 + ch.to_digit(radix)? as f64;
+    }
+    Some(value)
+}
+
+fn parse_decimal(literal: &str) -> Option<f64> {
+    if !separators_ok(literal, 10) {
+        return None;
+    }
+
+    let text: String = literal.chars().filter(|&ch| ch != '_').collect();
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(idx) => (&text[..idx], Some(&text[idx + 1..])),
+        None => (text.as_str(), None),
+    };
+    let (int_part, frac_part) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    let all_digits = |s: &str| s.chars().all(|ch| ch.is_ascii_digit());
+    if (int_part.is_empty() && frac_part.is_empty()) || !all_digits(int_part) || !all_digits(frac_part) {
+        return None;
+    }
+    if let Some(exponent) = exponent {
+        let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        if digits.is_empty() || !all_digits(digits) {
+            return None;
+        }
+    }
+
+    text.parse().ok()
+}
+
+/// Checks that every '_' in `digits` sits between two digits of `radix`.
+fn separators_ok(digits: &str, radix: u32) -> bool {
+    let chars: Vec<char> = digits.chars().collect();
+    chars.iter().enumerate().all(|(idx, &ch)| {
+        ch != '_' || (idx > 0
+            && idx + 1 < chars.len()
+            && chars[idx - 1].is_digit(radix)
+            && chars[idx + 1].is_digit(radix))
+    })
+}
@@ -1,5 +1,13 @@
 #[cfg(test)]
 mod test;
 
-mod lexer;
-mod parser;
+pub mod cli;
+pub mod error;
+pub mod eval;
+pub mod lexer;
+pub mod opt;
+pub mod parser;
+pub mod printer;
+pub mod repl;
+pub mod sema;
+pub mod trace;
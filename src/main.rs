@@ -1,3 +1,6 @@
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let stdin = std::io::BufReader::new(std::io::stdin());
+    let status = kaleidoscope::cli::run(&args, stdin, std::io::stdout(), std::io::stderr());
+    std::process::exit(status);
 }
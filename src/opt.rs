@@ -0,0 +1,166 @@
+use crate::eval::{builtin_binary, from_bool, is_truthy};
+use crate::lexer::Span;
+use crate::parser::{ExprAST, Item, Program, VarBinding};
+use std::fmt;
+
+/// One simplification made by `optimize`, e.g. `6 + 7` to `13`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rewrite {
+    pub before: String,
+    pub after: String,
+    /// Where the replaced expression was in the source.
+    pub span: Span,
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}\t{} => {}", self.span.start.line, self.span.start.column, self.before, self.after)
+    }
+}
+
+/// Folds constants and applies algebraic identities in every function body
+/// and top-level expression of `program`, returning what was changed.
+///
+/// The optimized program evaluates to exactly the same values as the
+/// original, down to NaN and the sign of zero, and performs the same calls
+/// in the same order.
+pub fn optimize(program: &mut Program) -> Vec<Rewrite> {
+    let mut rewrites = Vec::new();
+    for item in &mut program.items {
+        if let Item::Definition(function) | Item::TopLevelExpr(function) = item {
+            fold_in_place(&mut function.body, &mut rewrites);
+        }
+    }
+    rewrites
+}
+
+/// Simplifies `expr` bottom-up, so that e.g. `5 + (6 + 7) * 8` becomes
+/// `109`, and appends each step to `rewrites`.
+pub fn fold(expr: ExprAST, rewrites: &mut Vec<Rewrite>) -> ExprAST {
+    let expr = match expr {
+        ExprAST::NumberExprAST { .. } | ExprAST::VariableExprAST { .. } => return expr,
+        ExprAST::UnaryExprAST { op, operand, span } => {
+            ExprAST::UnaryExprAST { op, operand: fold_boxed(operand, rewrites), span }
+        },
+        ExprAST::BinaryExprAST { op, lhs, rhs, span } => {
+            let lhs = fold_boxed(lhs, rewrites);
+            ExprAST::BinaryExprAST { op, lhs, rhs: fold_boxed(rhs, rewrites), span }
+        },
+        ExprAST::LogicalExprAST { op, lhs, rhs, span } => {
+            let lhs = fold_boxed(lhs, rewrites);
+            ExprAST::LogicalExprAST { op, lhs, rhs: fold_boxed(rhs, rewrites), span }
+        },
+        ExprAST::CallExprAST { callee, args, span } => {
+            let args = args.into_iter().map(|arg| fold_boxed(arg, rewrites)).collect();
+            ExprAST::CallExprAST { callee, args, span }
+        },
+        ExprAST::IfExprAST { cond, then, else_, span } => {
+            let cond = fold_boxed(cond, rewrites);
+            let then = fold_boxed(then, rewrites);
+            ExprAST::IfExprAST { cond, then, else_: fold_boxed(else_, rewrites), span }
+        },
+        ExprAST::ForExprAST { var, var_span, start, end, step, body, span } => {
+            let start = fold_boxed(start, rewrites);
+            let end = fold_boxed(end, rewrites);
+            let step = step.map(|step| fold_boxed(step, rewrites));
+            ExprAST::ForExprAST { var, var_span, start, end, step, body: fold_boxed(body, rewrites), span }
+        },
+        ExprAST::VarExprAST { vars, body, span } => {
+            let vars = vars.into_iter()
+                .map(|VarBinding { name, init, span }| {
+                    VarBinding { name, init: init.map(|init| fold_boxed(init, rewrites)), span }
+                })
+                .collect();
+            ExprAST::VarExprAST { vars, body: fold_boxed(body, rewrites), span }
+        },
+        ExprAST::AssignExprAST { name, value, span } => {
+            ExprAST::AssignExprAST { name, value: fold_boxed(value, rewrites), span }
+        },
+        ExprAST::BlockExprAST { exprs, span } => {
+            let exprs = exprs.into_iter().map(|expr| fold_boxed(expr, rewrites)).collect();
+            ExprAST::BlockExprAST { exprs, span }
+        },
+    };
+
+    match simplified(&expr) {
+        Some(after) => {
+            rewrites.push(Rewrite { before: expr.to_string(), after: after.to_string(), span: expr.span() });
+            after
+        },
+        None => expr,
+    }
+}
+
+fn fold_in_place(expr: &mut ExprAST, rewrites: &mut Vec<Rewrite>) {
+    let placeholder = ExprAST::NumberExprAST { val: 0.0, span: expr.span() };
+    *expr = fold(std::mem::replace(expr, placeholder), rewrites);
+}
+
+fn fold_boxed(mut expr: Box<ExprAST>, rewrites: &mut Vec<Rewrite>) -> Box<ExprAST> {
+    fold_in_place(&mut expr, rewrites);
+    expr
+}
+
+fn number(expr: &ExprAST) -> Option<f64> {
+    match expr {
+        ExprAST::NumberExprAST { val, .. } => Some(*val),
+        _ => None,
+    }
+}
+
+/// The replacement for `expr`, whose operands are already folded, if it
+/// can be simplified. Only constants and branches that would never run
+/// are dropped, so no side effects are lost.
+fn simplified(expr: &ExprAST) -> Option<ExprAST> {
+    match expr {
+        ExprAST::UnaryExprAST { op, operand, span } if op == "-" => {
+            Some(ExprAST::NumberExprAST { val: -number(operand)?, span: *span })
+        },
+        ExprAST::BinaryExprAST { op, lhs, rhs, span } => match (number(lhs), number(rhs)) {
+            (Some(lhs), Some(rhs)) => {
+                builtin_binary(op, lhs, rhs).map(|val| ExprAST::NumberExprAST { val, span: *span })
+            },
+            (lhs_val, rhs_val) => match identity(op, lhs_val, rhs_val)? {
+                Operand::Lhs => Some((**lhs).clone()),
+                Operand::Rhs => Some((**rhs).clone()),
+            },
+        },
+        ExprAST::LogicalExprAST { op, lhs, rhs, span } => {
+            let val = match (op.as_str(), is_truthy(number(lhs)?)) {
+                ("&&", false) => 0.0,
+                ("||", true) => 1.0,
+                ("&&" | "||", _) => from_bool(is_truthy(number(rhs)?)),
+                _ => return None,
+            };
+            Some(ExprAST::NumberExprAST { val, span: *span })
+        },
+        ExprAST::IfExprAST { cond, then, else_, .. } => {
+            Some(if is_truthy(number(cond)?) { (**then).clone() } else { (**else_).clone() })
+        },
+        _ => None,
+    }
+}
+
+enum Operand {
+    Lhs,
+    Rhs,
+}
+
+/// Which operand `lhs op rhs` always equals, given the constant operands.
+///
+/// `x + 0` is deliberately missing: `-0 + 0` is `+0`, so it only holds with
+/// `-0` as the constant. Likewise `x - 0` needs a positive zero, `x * 0`
+/// fails for NaN, infinities and negative `x`, and `x ^ 1` can flip the sign
+/// bit of a NaN.
+fn identity(op: &str, lhs: Option<f64>, rhs: Option<f64>) -> Option<Operand> {
+    let is = |operand: Option<f64>, constant: f64| operand.is_some_and(|val| val.to_bits() == constant.to_bits());
+    match op {
+        "*" if is(rhs, 1.0) => Some(Operand::Lhs),
+        "*" if is(lhs, 1.0) => Some(Operand::Rhs),
+        "/" if is(rhs, 1.0) => Some(Operand::Lhs),
+        "+" if is(rhs, -0.0) => Some(Operand::Lhs),
+        "+" if is(lhs, -0.0) => Some(Operand::Rhs),
+        "-" if is(rhs, 0.0) => Some(Operand::Lhs),
+        _ => None,
+    }
+}
@@ -1,313 +1,979 @@
-use crate::lexer::{Token, Tokenizer, is_identifier, get_id};
+use crate::error::{ParseError, ParseErrorKind};
+use crate::lexer::{Span, Token, Tokenizer};
+use crate::trace::{TraceEvent, TraceSink};
 use std::collections::HashMap;
 
+/// Every node records the source span it was parsed from. The derived
+/// `PartialEq` compares spans and numbers exactly; use `eq_ignore_spans`
+/// to compare just the shape of two trees.
+#[derive(Debug, Clone, PartialEq)]
 pub enum ExprAST {
-    NumberExprAST { val: f64 },
-    VariableExprAST { name: String },
-    BinaryExprAST { op: String, lhs: Box<ExprAST>, rhs: Box<ExprAST> },
-    PrototypeAST { name: String, args: Vec<String> },
-    FunctionAST { proto: Box<ExprAST>, body: Box<ExprAST> },
-    CallExprAST  { callee: String, args: Vec<Box<ExprAST>> },
+    NumberExprAST { val: f64, span: Span },
+    VariableExprAST { name: String, span: Span },
+    UnaryExprAST { op: String, operand: Box<ExprAST>, span: Span },
+    BinaryExprAST { op: String, lhs: Box<ExprAST>, rhs: Box<ExprAST>, span: Span },
+    /// `&&` or `||`; `rhs` is only evaluated when `lhs` doesn't decide the result.
+    LogicalExprAST { op: String, lhs: Box<ExprAST>, rhs: Box<ExprAST>, span: Span },
+    CallExprAST  { callee: String, args: Vec<Box<ExprAST>>, span: Span },
+    IfExprAST { cond: Box<ExprAST>, then: Box<ExprAST>, else_: Box<ExprAST>, span: Span },
+    /// `for var = start, end, step in body`; `var` is only visible in `body`.
+    ForExprAST {
+        var: String,
+        /// The span of `var` alone.
+        var_span: Span,
+        start: Box<ExprAST>,
+        end: Box<ExprAST>,
+        step: Option<Box<ExprAST>>,
+        body: Box<ExprAST>,
+        span: Span,
+    },
+    /// `var a = 1, b in body`; variables without an initializer start at 0
+    /// and are only visible in later initializers and `body`.
+    VarExprAST { vars: Vec<VarBinding>, body: Box<ExprAST>, span: Span },
+    /// `name = value`, evaluating to `value`.
+    AssignExprAST { name: String, value: Box<ExprAST>, span: Span },
+    /// `{ a; b; c }`, evaluating to the last expression or 0 when empty.
+    BlockExprAST { exprs: Vec<Box<ExprAST>>, span: Span },
 }
 
+/// One `name = init` of a `var`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarBinding {
+    pub name: String,
+    pub init: Option<Box<ExprAST>>,
+    /// The span of `name` alone.
+    pub span: Span,
+}
+
+/// Function signature: `name(args)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrototypeAST {
+    pub name: String,
+    pub args: Vec<String>,
+    pub kind: PrototypeKind,
+    pub span: Span,
+}
+
+/// What a prototype declares. An operator's prototype is named after it,
+/// e.g. `binary|`, so that using the operator calls it like a function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrototypeKind {
+    Function,
+    UnaryOp,
+    BinaryOp { precedence: i32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionAST {
+    pub proto: PrototypeAST,
+    pub body: Box<ExprAST>,
+}
+
+/// Compares two numbers with a relative tolerance, treating NaN as equal to NaN.
+pub fn approx_eq(a: f64, b: f64) -> bool {
+    const EPSILON: f64 = 1e-9;
+    a == b || (a.is_nan() && b.is_nan()) || (a - b).abs() <= EPSILON * a.abs().max(b.abs()).max(1.0)
+}
+
+impl ExprAST {
+    pub fn span(&self) -> Span {
+        match self {
+            ExprAST::NumberExprAST { span, .. }
+            | ExprAST::VariableExprAST { span, .. }
+            | ExprAST::UnaryExprAST { span, .. }
+            | ExprAST::BinaryExprAST { span, .. }
+            | ExprAST::LogicalExprAST { span, .. }
+            | ExprAST::CallExprAST { span, .. }
+            | ExprAST::IfExprAST { span, .. }
+            | ExprAST::ForExprAST { span, .. }
+            | ExprAST::VarExprAST { span, .. }
+            | ExprAST::AssignExprAST { span, .. }
+            | ExprAST::BlockExprAST { span, .. } => *span,
+        }
+    }
+
+    /// Structural equality that ignores spans and compares numbers with `approx_eq`.
+    pub fn eq_ignore_spans(&self, other: &ExprAST) -> bool {
+        match (self, other) {
+            (ExprAST::NumberExprAST { val: a, .. }, ExprAST::NumberExprAST { val: b, .. }) => approx_eq(*a, *b),
+            (ExprAST::VariableExprAST { name: a, .. }, ExprAST::VariableExprAST { name: b, .. }) => a == b,
+            (ExprAST::UnaryExprAST { op: op_a, operand: operand_a, .. },
+             ExprAST::UnaryExprAST { op: op_b, operand: operand_b, .. }) => {
+                op_a == op_b && operand_a.eq_ignore_spans(operand_b)
+            },
+            (ExprAST::BinaryExprAST { op: op_a, lhs: lhs_a, rhs: rhs_a, .. },
+             ExprAST::BinaryExprAST { op: op_b, lhs: lhs_b, rhs: rhs_b, .. })
+            | (ExprAST::LogicalExprAST { op: op_a, lhs: lhs_a, rhs: rhs_a, .. },
+               ExprAST::LogicalExprAST { op: op_b, lhs: lhs_b, rhs: rhs_b, .. }) => {
+                op_a == op_b && lhs_a.eq_ignore_spans(lhs_b) && rhs_a.eq_ignore_spans(rhs_b)
+            },
+            (ExprAST::CallExprAST { callee: callee_a, args: args_a, .. },
+             ExprAST::CallExprAST { callee: callee_b, args: args_b, .. }) => {
+                callee_a == callee_b && all_eq_ignore_spans(args_a, args_b)
+            },
+            (ExprAST::IfExprAST { cond: cond_a, then: then_a, else_: else_a, .. },
+             ExprAST::IfExprAST { cond: cond_b, then: then_b, else_: else_b, .. }) => {
+                cond_a.eq_ignore_spans(cond_b) && then_a.eq_ignore_spans(then_b) && else_a.eq_ignore_spans(else_b)
+            },
+            (ExprAST::ForExprAST { var: var_a, start: start_a, end: end_a, step: step_a, body: body_a, .. },
+             ExprAST::ForExprAST { var: var_b, start: start_b, end: end_b, step: step_b, body: body_b, .. }) => {
+                var_a == var_b
+                    && start_a.eq_ignore_spans(start_b)
+                    && end_a.eq_ignore_spans(end_b)
+                    && opt_eq_ignore_spans(step_a, step_b)
+                    && body_a.eq_ignore_spans(body_b)
+            },
+            (ExprAST::VarExprAST { vars: vars_a, body: body_a, .. },
+             ExprAST::VarExprAST { vars: vars_b, body: body_b, .. }) => {
+                vars_a.len() == vars_b.len()
+                    && vars_a.iter().zip(vars_b).all(|(a, b)| a.name == b.name && opt_eq_ignore_spans(&a.init, &b.init))
+                    && body_a.eq_ignore_spans(body_b)
+            },
+            (ExprAST::BlockExprAST { exprs: exprs_a, .. }, ExprAST::BlockExprAST { exprs: exprs_b, .. }) => {
+                all_eq_ignore_spans(exprs_a, exprs_b)
+            },
+            (ExprAST::AssignExprAST { name: name_a, value: value_a, .. },
+             ExprAST::AssignExprAST { name: name_b, value: value_b, .. }) => {
+                name_a == name_b && value_a.eq_ignore_spans(value_b)
+            },
+            _ => false,
+        }
+    }
+}
+
+fn all_eq_ignore_spans(a: &[Box<ExprAST>], b: &[Box<ExprAST>]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_ignore_spans(b))
+}
+
+fn opt_eq_ignore_spans(a: &Option<Box<ExprAST>>, b: &Option<Box<ExprAST>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.eq_ignore_spans(b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+impl PrototypeAST {
+    pub fn eq_ignore_spans(&self, other: &PrototypeAST) -> bool {
+        self.name == other.name && self.args == other.args && self.kind == other.kind
+    }
+
+    /// The operator a `unary` or `binary` prototype defines, if any.
+    pub fn operator(&self) -> Option<&str> {
+        let prefix = match self.kind {
+            PrototypeKind::Function => return None,
+            PrototypeKind::UnaryOp => UNARY_PREFIX,
+            PrototypeKind::BinaryOp { .. } => BINARY_PREFIX,
+        };
+        self.name.strip_prefix(prefix)
+    }
+}
+
+impl FunctionAST {
+    pub fn eq_ignore_spans(&self, other: &FunctionAST) -> bool {
+        self.proto.eq_ignore_spans(&other.proto) && self.body.eq_ignore_spans(&other.body)
+    }
+}
+
+/// Prefix of the function name a user-defined unary operator is stored under.
+pub const UNARY_PREFIX: &str = "unary";
+
+/// Prefix of the function name a user-defined binary operator is stored under.
+pub const BINARY_PREFIX: &str = "binary";
+
+/// Precedence of a user-defined binary operator declared without one.
+pub const DEFAULT_OPERATOR_PRECEDENCE: i32 = 30;
+
+/// Range of precedences a user-defined binary operator may declare.
+pub const OPERATOR_PRECEDENCE_RANGE: std::ops::RangeInclusive<i32> = 1..=100;
+
+/// Name of the prototype wrapping a top-level expression.
+pub const ANON_FUNCTION_NAME: &str = "__anon_expr";
+
+/// Anything that can appear at the top level of a source file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    /// `extern name(args)`
+    Extern(PrototypeAST),
+    /// `def name(args) body`
+    Definition(FunctionAST),
+    /// An expression to evaluate, wrapped in a function named `ANON_FUNCTION_NAME`.
+    TopLevelExpr(FunctionAST),
+}
+
+impl Item {
+    pub fn eq_ignore_spans(&self, other: &Item) -> bool {
+        match (self, other) {
+            (Item::Extern(a), Item::Extern(b)) => a.eq_ignore_spans(b),
+            (Item::Definition(a), Item::Definition(b)) | (Item::TopLevelExpr(a), Item::TopLevelExpr(b)) => {
+                a.eq_ignore_spans(b)
+            },
+            _ => false,
+        }
+    }
+}
+
+/// A parsed source file: its items in source order, along with the errors
+/// encountered while parsing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub items: Vec<Item>,
+    pub errors: Vec<ParseError>,
+}
+
+impl Program {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Parses a complete in-memory source file.
+pub fn parse_source(source: &[u8]) -> Program {
+    parse_source_with_operators(source, &mut default_operators())
+}
+
+/// Parses `source` starting from the operator table `prec`, and leaves the
+/// operators the source defines in it for the next call.
+pub fn parse_source_with_operators(mut source: &[u8], prec: &mut OperatorTable) -> Program {
+    let mut lexer = Tokenizer::new(&mut source);
+    let mut parser = Parser::new(&mut lexer);
+    parser.set_operators(std::mem::take(prec));
+    let program = parser.parse();
+    *prec = parser.take_operators();
+    program
+}
+
+/// How operators of the same precedence group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Associativity {
+    /// `a - b - c` is `(a - b) - c`.
+    Left,
+    /// `a ^ b ^ c` is `a ^ (b ^ c)`.
+    Right,
+    /// `a < b < c` is an error.
+    NonAssoc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OperatorInfo {
+    /// Higher binds tighter.
+    pub precedence: i32,
+    pub associativity: Associativity,
+}
+
+impl OperatorInfo {
+    pub fn new(precedence: i32, associativity: Associativity) -> Self {
+        Self { precedence, associativity }
+    }
+}
+
+/// Binary operators by spelling.
+pub type OperatorTable = HashMap<String, OperatorInfo>;
+
+/// The built-in binary operators.
+pub fn default_operators() -> OperatorTable {
+    use Associativity::*;
+    let mut table = OperatorTable::new();
+    let mut add = |ops: &[&str], precedence, associativity| {
+        for op in ops {
+            table.insert(op.to_string(), OperatorInfo::new(precedence, associativity));
+        }
+    };
+    add(&["="], 2, Right);
+    add(&["||"], 4, Left);
+    add(&["&&"], 6, Left);
+    add(&["==", "!="], 8, NonAssoc);
+    add(&["<", ">", "<=", ">="], 10, NonAssoc);
+    add(&["+", "-"], 20, Left);
+    add(&["*", "/", "%"], 30, Left);
+    add(&["^"], 40, Right);
+    table
+}
+
+/// The assignment operator, which becomes an `AssignExprAST`.
+pub const ASSIGN_OPERATOR: &str = "=";
+
+/// Binary operators that short-circuit and so become a `LogicalExprAST`.
+pub const LOGICAL_OPERATORS: [&str; 2] = ["&&", "||"];
+
+/// Prefix operators the interpreter implements itself.
+pub const BUILTIN_UNARY_OPERATORS: [&str; 1] = ["-"];
+
+pub type ParseResult<T> = Result<T, Box<ParseError>>;
+
 pub struct Parser<'a> {
     lexer: &'a mut Tokenizer<'a>,
-    prec: HashMap<String, i32>
+    prec: OperatorTable,
+    trace: Option<Box<dyn TraceSink>>,
+    /// Blocks entered but not yet closed, so that after an error
+    /// `synchronize` knows how many `}` still belong to the broken item.
+    open_blocks: usize,
 }
 
 
 /// Grammar:
 impl<'a> Parser<'a> {
     pub fn new(lexer: &'a mut Tokenizer<'a>) -> Self {
-        let mut default_prec = HashMap::<String, i32>::new();
-        default_prec.insert("<".to_string(), 10);
-        default_prec.insert("+".to_string(), 20);
-        default_prec.insert("-".to_string(), 20);
-        default_prec.insert("*".to_string(), 30);
         Self {
-           lexer: lexer,
-           prec: default_prec
+           lexer,
+           prec: default_operators(),
+           trace: None,
+           open_blocks: 0,
         }
     }
 
-    pub fn parse(&mut self) {
+    /// Reports the grammar rules the parser goes through to `sink`.
+    pub fn set_trace_sink(&mut self, sink: Box<dyn TraceSink>) {
+        self.trace = Some(sink);
+    }
+
+    /// Replaces the binary operator table.
+    pub fn set_operators(&mut self, prec: OperatorTable) {
+        self.prec = prec;
+    }
+
+    /// Returns the operator table, including the operators defined so far,
+    /// leaving an empty one behind.
+    pub fn take_operators(&mut self) -> OperatorTable {
+        std::mem::take(&mut self.prec)
+    }
+
+    /// Parses the whole input into a `Program`. After an error the parser
+    /// skips ahead to the next item boundary and carries on, so a single
+    /// mistake doesn't hide the errors that follow it.
+    ///
+    /// program
+    ///     ::= (item | ';')*
+    pub fn parse(&mut self) -> Program {
+        let mut program = Program::default();
         loop {
-            match self.lexer.last_token() {
+            let item_start = self.lexer.last_span().start;
+            let item = match self.lexer.last_token() {
                 Token::Eof => break,
+                Token::Semicolon => {
+                    self.lexer.next(); // eat ';'
+                    continue;
+                },
                 Token::Def => self.handle_definition(),
                 Token::Extern => self.handle_extern(),
                 _ => self.handle_top_level_expression(),
+            };
+            match item {
+                Ok(item) => program.items.push(item),
+                Err(err) => {
+                    program.errors.push(*err);
+                    if self.lexer.last_span().start == item_start {
+                        self.lexer.next(); // always make progress
+                    }
+                    self.synchronize();
+                }
             }
         }
+        program
     }
 
+    /// Skips tokens until one that can start a new top-level item, or the
+    /// end of the broken one: a `;` outside of blocks, or the `}` closing
+    /// the block the error was in.
+    fn synchronize(&mut self) {
+        let mut skipped = 0;
+        let mut depth = std::mem::take(&mut self.open_blocks);
+        loop {
+            match self.lexer.last_token() {
+                Token::Eof | Token::Def | Token::Extern => break,
+                Token::Semicolon if depth == 0 => break,
+                Token::LBrace => depth += 1,
+                Token::RBrace if depth == 1 => {
+                    self.lexer.next(); // eat '}'
+                    skipped += 1;
+                    break;
+                },
+                Token::RBrace if depth > 1 => depth -= 1,
+                _ => {},
+            }
+            self.lexer.next();
+            skipped += 1;
+        }
+        if let Some(sink) = &mut self.trace {
+            sink.event(&TraceEvent::Synchronized { skipped, span: self.lexer.last_span() });
+        }
+    }
 
-    fn handle_top_level_expression(&mut self) {
-        self.parse_top_level_expr();
+    fn handle_top_level_expression(&mut self) -> ParseResult<Item> {
+        self.parse_top_level_expr().map(Item::TopLevelExpr)
     }
 
-    fn handle_extern(&mut self) {
-        self.parse_extern();
+    fn handle_extern(&mut self) -> ParseResult<Item> {
+        self.parse_extern().map(Item::Extern)
     }
 
-    fn handle_definition(&mut self) {
-        self.parse_definition();
+    fn handle_definition(&mut self) -> ParseResult<Item> {
+        self.parse_definition().map(Item::Definition)
     }
 
-    fn parse_extern(&mut self) -> Option<Box<ExprAST>> {
+    /// external
+    ///     ::= 'extern' prototype
+    pub fn parse_extern(&mut self) -> ParseResult<PrototypeAST> {
+        self.enter("extern");
         self.lexer.next(); // eat `extern`
         self.parse_prototype()
     }
 
-    fn parse_definition(&mut self) -> Option<Box<ExprAST>> {
+    /// definition
+    ///     ::= 'def' prototype expression
+    pub fn parse_definition(&mut self) -> ParseResult<FunctionAST> {
+        self.enter("definition");
         self.lexer.next(); // eat `def`
-        let Some(proto) = self.parse_prototype() else {
-            return None;
-        };
+        let proto = self.parse_prototype()?;
+        let mut registered = None;
+        if let (Some(op), PrototypeKind::BinaryOp { precedence }) = (proto.operator(), proto.kind) {
+            // registered before the body so that the operator can recurse
+            let previous = self.prec.insert(op.to_string(), OperatorInfo::new(precedence, Associativity::Left));
+            registered = Some((op.to_string(), previous));
+        }
 
-        let Some(body) = self.parse_expression() else {
-            return None;
+        let body = match self.parse_expression() {
+            Ok(body) => body,
+            Err(err) => {
+                // without a body there is no definition, so forget the operator again
+                if let Some((op, previous)) = registered {
+                    match previous {
+                        Some(previous) => self.prec.insert(op, previous),
+                        None => self.prec.remove(&op),
+                    };
+                }
+                return Err(err);
+            },
         };
-        
-        Some(Box::new(ExprAST::FunctionAST { proto, body }))
+
+        Ok(FunctionAST { proto, body })
     }
 
-    pub fn parse_top_level_expr(&mut self) -> Option<Box<ExprAST>> {
-        let Some(body) = self.parse_expression() else {
-            return None;
-        };
+    /// toplevelexpr
+    ///     ::= expression
+    pub fn parse_top_level_expr(&mut self) -> ParseResult<FunctionAST> {
+        self.enter("toplevelexpr");
+        let body = self.parse_expression()?;
 
-        let proto = Box::new(ExprAST::PrototypeAST { name: String::from("__anon_expr"), args: Vec::new() });
-        Some(Box::new(ExprAST::FunctionAST { proto, body }))
+        let proto = PrototypeAST { name: String::from(ANON_FUNCTION_NAME), args: Vec::new(), kind: PrototypeKind::Function, span: body.span() };
+        Ok(FunctionAST { proto, body })
     }
 
     /// primary
     ///     ::= parenexpr
     ///     ::= identifierexpr
     ///     ::= numberexpr
-    fn parse_primary(&mut self) -> Option<Box<ExprAST>> {
-        println!("parse_primary");
-        if let Token::Identifier{id} = self.lexer.last_token() {
-            if id == "(" {
-                println!("goto parse_paren_expr");
-                return self.parse_paren_expr();
-            } else {
-                println!("goto parse_identifier_expr");
-                return self.parse_identifier_expr();
-            }
-        } else if let Token::Number{..} = self.lexer.last_token() {
-            println!("goto parse_number_expr");
-            return self.parse_number_expr();
+    ///     ::= ifexpr
+    ///     ::= forexpr
+    ///     ::= varexpr
+    ///     ::= blockexpr
+    fn parse_primary(&mut self) -> ParseResult<Box<ExprAST>> {
+        self.enter("primary");
+        match self.lexer.last_token() {
+            Token::LParen => self.parse_paren_expr(),
+            Token::Identifier{..} => self.parse_identifier_expr(),
+            Token::Number{..} => self.parse_number_expr(),
+            Token::If => self.parse_if_expr(),
+            Token::For => self.parse_for_expr(),
+            Token::Var => self.parse_var_expr(),
+            Token::LBrace => self.parse_block_expr(),
+            _ => self.error("Expected expression", &["expression"])
         }
-        return self.log_error("Expected expression, got unknown token");
     }
 
 
     /// numberexpr
     ///     ::= number
-    fn parse_number_expr(&mut self) -> Option<Box<ExprAST>> {
+    fn parse_number_expr(&mut self) -> ParseResult<Box<ExprAST>> {
+        self.enter("numberexpr");
         let Token::Number {value} = self.lexer.last_token() else {
-            return self.log_error("Expected number");
+            return self.error("Expected number", &["number"]);
         };
 
+        let span = self.lexer.last_span();
         self.lexer.next(); // eat number
         
-        Some(Box::new(ExprAST::NumberExprAST { val: value }))
+        Ok(Box::new(ExprAST::NumberExprAST { val: value, span }))
     }
 
     /// parenexpr
     ///     ::= '(' expression ')'
-    fn parse_paren_expr(&mut self) -> Option<Box<ExprAST>> {
+    fn parse_paren_expr(&mut self) -> ParseResult<Box<ExprAST>> {
+        self.enter("parenexpr");
         self.lexer.next(); // eat (.
-        let inner = self.parse_expression();
-        match self.lexer.last_token() {
-            Token::Identifier{id} => {
-                if id != ")" {
-                    return self.log_error("Expected ')'");
-                }
-            },
-            _ => return self.log_error("Expected ')'")
+        let inner = self.parse_expression()?;
+        if self.lexer.last_token() != Token::RParen {
+            return self.error("Expected ')'", &["')'"]);
         }
         self.lexer.next(); // eat ')'
-        return inner;
+        Ok(inner)
     }
 
-    ///  expression
-    ///     ::= primary binoprhs
-    pub fn parse_expression(&mut self) -> Option<Box<ExprAST>> {
-        let lhs = self.parse_primary();
-        match lhs {
-            None => None,
-            Some(_) => self.parse_binop_rhs(0, lhs)
+    /// ifexpr
+    ///     ::= 'if' expression 'then' expression 'else' expression
+    fn parse_if_expr(&mut self) -> ParseResult<Box<ExprAST>> {
+        self.enter("ifexpr");
+        let start = self.lexer.last_span();
+        self.lexer.next(); // eat `if`
+        let cond = self.parse_expression()?;
+
+        if self.lexer.last_token() != Token::Then {
+            return self.error("Expected 'then'", &["'then'"]);
         }
+        self.lexer.next(); // eat `then`
+        let then = self.parse_expression()?;
+
+        if self.lexer.last_token() != Token::Else {
+            return self.error("Expected 'else'", &["'else'"]);
+        }
+        self.lexer.next(); // eat `else`
+        let else_ = self.parse_expression()?;
+
+        let span = start.to(else_.span());
+        Ok(Box::new(ExprAST::IfExprAST { cond, then, else_, span }))
     }
 
+    /// forexpr
+    ///     ::= 'for' identifier '=' expression ',' expression (',' expression)? 'in' expression
+    fn parse_for_expr(&mut self) -> ParseResult<Box<ExprAST>> {
+        self.enter("forexpr");
+        let start_span = self.lexer.last_span();
+        self.lexer.next(); // eat `for`
 
-    /// identifierexpr
-    ///   ::= identifier
-    ///   ::= identifier '(' expression* ')'    <---- function definition/call or prototype;
-    pub fn parse_identifier_expr(&mut self) -> Option<Box<ExprAST>> {
-        println!("parse_identifier_expr");
-        let Token::Identifier { id } = self.lexer.last_token() else {
-           return self.log_error("parse_identifier_expr() expected identifier");
+        let Token::Identifier { id: var } = self.lexer.last_token() else {
+            return self.error("Expected identifier after 'for'", &["identifier"]);
         };
+        let var_span = self.lexer.last_span();
+        self.lexer.next(); // eat identifier
 
-        self.lexer.next(); // eat id
+        if self.lexer.last_token() != (Token::Operator { op: String::from("=") }) {
+            return self.error("Expected '=' after for loop variable", &["'='"]);
+        }
+        self.lexer.next(); // eat '='
 
-        if let Token::Identifier {id: id_next} = self.lexer.last_token() {
-            // simple var
-            if id_next != "(" {
-                return Some(Box::new(ExprAST::VariableExprAST{name: id.to_string()})); 
-            }
-        } else {
-            return Some(Box::new(ExprAST::VariableExprAST{name: id.to_string()})); 
+        let start = self.parse_expression()?;
+        if self.lexer.last_token() != Token::Comma {
+            return self.error("Expected ',' after for loop start value", &["','"]);
         }
+        self.lexer.next(); // eat ','
 
-        // function call
-        self.lexer.next(); // eat '('
+        let end = self.parse_expression()?;
 
-        // get args
-        let mut args = Vec::<Box<ExprAST>>::new();
+        let mut step = None;
+        if self.lexer.last_token() == Token::Comma {
+            self.lexer.next(); // eat ','
+            step = Some(self.parse_expression()?);
+        }
 
-        if !is_identifier(&self.lexer.last_token()) || get_id(&self.lexer.last_token()) != ")" {
-            loop {
-                println!("looping");
-                let Some(arg) = self.parse_expression() else {
-                    return None;
-                };
+        if self.lexer.last_token() != Token::In {
+            return self.error("Expected 'in' after for loop header", &["'in'"]);
+        }
+        self.lexer.next(); // eat `in`
 
-                args.push(arg);
+        let body = self.parse_expression()?;
 
-                if !is_identifier(&self.lexer.last_token()) {
-                    return self.log_error("Expected identifier");
-                }
+        let span = start_span.to(body.span());
+        Ok(Box::new(ExprAST::ForExprAST { var, var_span, start, end, step, body, span }))
+    }
+
+    /// varexpr
+    ///     ::= varbindings 'in' expression
+    fn parse_var_expr(&mut self) -> ParseResult<Box<ExprAST>> {
+        self.enter("varexpr");
+        let start_span = self.lexer.last_span();
+        let vars = self.parse_var_bindings()?;
+
+        if self.lexer.last_token() != Token::In {
+            return self.error("Expected 'in' after 'var' bindings", &["'in'", "','"]);
+        }
+        self.lexer.next(); // eat `in`
+
+        let body = self.parse_expression()?;
+
+        let span = start_span.to(body.span());
+        Ok(Box::new(ExprAST::VarExprAST { vars, body, span }))
+    }
 
-                if let Token::Identifier {id} = self.lexer.last_token() {
-                    if id == ")" {
-                        continue;
-                    } else if id == "," {
-                        self.lexer.next(); 
-                    } else {
-                        return self.log_error("Expected ')' or ',' in arg list");
+    /// varbindings
+    ///     ::= 'var' identifier ('=' expression)? (',' identifier ('=' expression)?)*
+    fn parse_var_bindings(&mut self) -> ParseResult<Vec<VarBinding>> {
+        self.lexer.next(); // eat `var`
+
+        let mut vars = Vec::new();
+        loop {
+            let Token::Identifier { id: name } = self.lexer.last_token() else {
+                return self.error("Expected identifier in 'var'", &["identifier"]);
+            };
+            let span = self.lexer.last_span();
+            self.lexer.next(); // eat identifier
+
+            let mut init = None;
+            if self.lexer.last_token() == (Token::Operator { op: String::from(ASSIGN_OPERATOR) }) {
+                self.lexer.next(); // eat '='
+                init = Some(self.parse_expression()?);
+            }
+            vars.push(VarBinding { name, init, span });
+
+            if self.lexer.last_token() != Token::Comma {
+                return Ok(vars);
+            }
+            self.lexer.next(); // eat ','
+        }
+    }
+
+    /// blockexpr
+    ///     ::= '{' (statement (';' statement)* ';'?)? '}'
+    fn parse_block_expr(&mut self) -> ParseResult<Box<ExprAST>> {
+        self.enter("blockexpr");
+        let start = self.lexer.last_span();
+        self.lexer.next(); // eat '{'
+        self.open_blocks += 1;
+        let (exprs, end) = self.parse_statements()?;
+        self.open_blocks -= 1;
+        Ok(Box::new(ExprAST::BlockExprAST { exprs, span: start.to(end) }))
+    }
+
+    /// statement
+    ///     ::= expression
+    ///     ::= varbindings
+    ///
+    /// Parses the statements of a block up to and including the '}' and
+    /// returns them with the span of the '}'. A `var` without `in` is
+    /// visible in the rest of the block, so it becomes a `VarExprAST`
+    /// whose body is a block of the statements after it.
+    #[allow(clippy::vec_box)] // matches `BlockExprAST::exprs`
+    fn parse_statements(&mut self) -> ParseResult<(Vec<Box<ExprAST>>, Span)> {
+        let mut exprs = Vec::new();
+        loop {
+            if self.lexer.last_token() == Token::RBrace {
+                let end = self.lexer.last_span();
+                self.lexer.next(); // eat '}'
+                return Ok((exprs, end));
+            }
+
+            let expr = if self.lexer.last_token() == Token::Var {
+                let start = self.lexer.last_span();
+                let vars = self.parse_var_bindings()?;
+                if self.lexer.last_token() != Token::In {
+                    if self.lexer.last_token() == Token::Semicolon {
+                        self.lexer.next(); // eat ';'
+                    } else if self.lexer.last_token() != Token::RBrace {
+                        return self.error("Expected 'in', ';' or '}' after 'var' bindings", &["'in'", "';'", "'}'"]);
                     }
-                } else {
-                    return self.log_error("Expected ')' or ',' in arg list");
-                };
+                    let rest_start = self.lexer.last_span();
+                    let (rest, end) = self.parse_statements()?;
+                    let body = Box::new(ExprAST::BlockExprAST { exprs: rest, span: rest_start.to(end) });
+                    exprs.push(Box::new(ExprAST::VarExprAST { vars, body, span: start.to(end) }));
+                    return Ok((exprs, end));
+                }
+                self.lexer.next(); // eat `in`
+                let body = self.parse_expression()?;
+                let span = start.to(body.span());
+                Box::new(ExprAST::VarExprAST { vars, body, span })
+            } else {
+                self.parse_expression()?
+            };
+            exprs.push(expr);
 
+            match self.lexer.last_token() {
+                Token::RBrace => {},
+                Token::Semicolon => self.lexer.next(),
+                _ => return self.error("Expected ';' or '}' in block", &["';'", "'}'"]),
             }
         }
+    }
 
+    ///  expression
+    ///     ::= unary binoprhs
+    pub fn parse_expression(&mut self) -> ParseResult<Box<ExprAST>> {
+        self.enter("expression");
+        let lhs = self.parse_unary()?;
+        self.parse_binop_rhs(0, lhs)
+    }
 
-        self.lexer.next(); // eat ')'
-        return Some(Box::new(ExprAST::CallExprAST {
-            callee: id.to_string(),
-            args: args,
-        }));
+    /// unary
+    ///     ::= primary
+    ///     ::= operator unary
+    fn parse_unary(&mut self) -> ParseResult<Box<ExprAST>> {
+        self.enter("unary");
+        let Token::Operator { op } = self.lexer.last_token() else {
+            return self.parse_primary();
+        };
+
+        let start = self.lexer.last_span();
+        self.lexer.next(); // eat operator
+        let operand = self.parse_unary()?;
+
+        let span = start.to(operand.span());
+        Ok(Box::new(ExprAST::UnaryExprAST { op, operand, span }))
     }
 
 
-    /// binoprhs
-    ///     ::= ('+' primary)*
-    pub fn parse_binop_rhs(&mut self, prec: i32, option_lhs: Option<Box<ExprAST>>) -> Option<Box<ExprAST>> { 
-        println!("parse_binop_rhs");
-        let Some(mut lhs) = option_lhs else {
-            return self.log_error("lhs should be non-null");
+    /// identifierexpr
+    ///   ::= identifier
+    ///   ::= identifier '(' list(expression) ')'
+    pub fn parse_identifier_expr(&mut self) -> ParseResult<Box<ExprAST>> {
+        self.enter("identifierexpr");
+        let Token::Identifier { id } = self.lexer.last_token() else {
+           return self.error("Expected identifier", &["identifier"]);
         };
 
+        let start = self.lexer.last_span();
+        self.lexer.next(); // eat id
+
+        if self.lexer.last_token() != Token::LParen {
+            // simple var
+            return Ok(Box::new(ExprAST::VariableExprAST{name: id.to_string(), span: start})); 
+        }
+
+        // function call
+        self.lexer.next(); // eat '('
+        let (args, end) = self.parse_list("argument list", |parser| parser.parse_expression())?;
+
+        let span = start.to(end);
+        Ok(Box::new(ExprAST::CallExprAST {
+            callee: id.to_string(),
+            args,
+            span,
+        }))
+    }
+
+
+    /// binoprhs
+    ///     ::= ('+' unary)*
+    ///
+    /// Parses operators binding at least as tightly as `prec`.
+    pub fn parse_binop_rhs(&mut self, prec: i32, mut lhs: Box<ExprAST>) -> ParseResult<Box<ExprAST>> { 
+        self.enter("binoprhs");
+
+        // the previous operator at this level, to catch `a < b < c`
+        let mut last: Option<(String, OperatorInfo)> = None;
         loop {
-            println!("looping in binop_rhs");
             let tok_prec = self.get_precedence(&self.lexer.last_token());
             if tok_prec < prec {
                 // not a binop
-                return Some(lhs);
+                return Ok(lhs);
             }
 
             // binop
-            let Token::Identifier{id: binop_id} = self.lexer.last_token() else {
-                return self.log_error("Expected binary operation identifier");
+            let Token::Operator{op: binop_id} = self.lexer.last_token() else {
+                return self.error("Expected binary operator", &["binary operator"]);
             };
 
-            println!("Checking binop '{}", binop_id);
+            let Some(info) = self.prec.get(&binop_id).copied() else {
+                return self.error("Expected binary operator", &["binary operator"]);
+            };
 
-            if !self.prec.contains_key(&binop_id) {
-                return self.log_error("Expected binary operator");
+            if let Some((last_op, last_info)) = &last {
+                let non_assoc = info.associativity == Associativity::NonAssoc
+                    || last_info.associativity == Associativity::NonAssoc;
+                if non_assoc && last_info.precedence == info.precedence {
+                    return Err(Box::new(ParseError::with_kind(
+                        ParseErrorKind::NonAssociative,
+                        &format!("Operator '{binop_id}' cannot be chained with '{last_op}', add parentheses"),
+                        self.lexer.last_token(),
+                        self.lexer.last_span(),
+                    )));
+                }
             }
 
-            self.lexer.next(); // eat binop
-            let Some(mut rhs) = self.parse_primary() else {
-                return None;
+            if let Some(sink) = &mut self.trace {
+                sink.event(&TraceEvent::BinaryOperator { op: binop_id.clone(), precedence: tok_prec });
+            }
+
+            let assign_to = match &*lhs {
+                _ if binop_id != ASSIGN_OPERATOR => None,
+                ExprAST::VariableExprAST { name, .. } => Some(name.clone()),
+                _ => return Err(Box::new(ParseError::with_kind(
+                    ParseErrorKind::InvalidAssignment,
+                    "Only a variable can be assigned to",
+                    self.lexer.last_token(),
+                    lhs.span(),
+                ))),
             };
 
+            self.lexer.next(); // eat binop
+            let mut rhs = self.parse_unary()?;
+
             let next_prec = self.get_precedence(&self.lexer.last_token());
-            if tok_prec < next_prec {
+            if info.associativity == Associativity::Right && tok_prec <= next_prec {
+                // case like: A ^ B ^ C
+                rhs = self.parse_binop_rhs(tok_prec, rhs)?;
+            } else if tok_prec < next_prec {
                 // case like: A + B * C
-                let Some(new_rhs) = self.parse_binop_rhs(tok_prec + 1, Some(rhs)) else {
-                    return None;
-                };
-                rhs = new_rhs;
+                rhs = self.parse_binop_rhs(tok_prec + 1, rhs)?;
             }
 
-            lhs = Box::new(ExprAST::BinaryExprAST{
-                op: binop_id.to_string(),
-                lhs: lhs,
-                rhs: rhs
-            });
+            let span = lhs.span().to(rhs.span());
+            lhs = if LOGICAL_OPERATORS.contains(&binop_id.as_str()) {
+                Box::new(ExprAST::LogicalExprAST { op: binop_id.clone(), lhs, rhs, span })
+            } else if let Some(name) = assign_to {
+                Box::new(ExprAST::AssignExprAST { name, value: rhs, span })
+            } else {
+                Box::new(ExprAST::BinaryExprAST{
+                    op: binop_id.to_string(),
+                    lhs,
+                    rhs,
+                    span,
+                })
+            };
+            last = Some((binop_id, info));
         }
     }
 
 
-    fn parse_prototype(&mut self) -> Option<Box<ExprAST>> {
-        let Token::Identifier { id: func_name } = self.lexer.last_token() else {
-            return self.log_error("Expected identifier in prototype");
-        };
-
-        self.lexer.next(); // eat name
-
-        let Token::Identifier { id } = self.lexer.last_token() else {
-            return self.log_error("Expected '(' after identifier in prototype");
+    /// prototype
+    ///     ::= identifier '(' list(identifier) ')'
+    ///     ::= 'unary' operator '(' identifier ','? ')'
+    ///     ::= 'binary' operator number? '(' identifier ',' identifier ','? ')'
+    fn parse_prototype(&mut self) -> ParseResult<PrototypeAST> {
+        self.enter("prototype");
+        let start = self.lexer.last_span();
+        let (func_name, kind) = match self.lexer.last_token() {
+            Token::Identifier { id } => {
+                self.lexer.next(); // eat name
+                (id, PrototypeKind::Function)
+            },
+            Token::Unary => {
+                self.lexer.next(); // eat `unary`
+                let op = self.parse_operator_name("unary", |op| BUILTIN_UNARY_OPERATORS.contains(&op))?;
+                (format!("{UNARY_PREFIX}{op}"), PrototypeKind::UnaryOp)
+            },
+            Token::Binary => {
+                self.lexer.next(); // eat `binary`
+                let op = self.parse_operator_name("binary", |op| default_operators().contains_key(op))?;
+                let precedence = self.parse_operator_precedence()?;
+                (format!("{BINARY_PREFIX}{op}"), PrototypeKind::BinaryOp { precedence })
+            },
+            _ => return self.error("Expected function name in prototype", &["identifier", "'unary'", "'binary'"]),
         };
 
-        if id != "(" {
-            return self.log_error("Expected '(' after identifier in prototype");
+        if self.lexer.last_token() != Token::LParen {
+            return self.error("Expected '(' after identifier in prototype", &["'('"]);
         }
 
         self.lexer.next(); // eat '('
+        let (params, end) = self.parse_list("parameter list", |parser| {
+            let Token::Identifier { id: param } = parser.lexer.last_token() else {
+                return parser.error("Expected identifier in prototype arguments", &["identifier", "')'"]);
+            };
+            let span = parser.lexer.last_span();
+            parser.lexer.next(); // eat identifier
+            Ok((param, span))
+        })?;
+
+        for (idx, (param, span)) in params.iter().enumerate() {
+            if params[..idx].iter().any(|(earlier, _)| earlier == param) {
+                return Err(Box::new(ParseError::with_kind(
+                    ParseErrorKind::DuplicateParameter,
+                    &format!("Parameter '{param}' is declared more than once"),
+                    Token::Identifier { id: param.clone() },
+                    *span,
+                )));
+            }
+        }
+        let func_args = params.into_iter().map(|(param, _)| param).collect();
+
+        let proto = PrototypeAST { name: func_name, args: func_args, kind, span: start.to(end) };
+        if let Some(op) = proto.operator() {
+            let operands = if proto.kind == PrototypeKind::UnaryOp { 1 } else { 2 };
+            if proto.args.len() != operands {
+                return Err(Box::new(ParseError::with_kind(
+                    ParseErrorKind::InvalidOperator,
+                    &format!("Operator '{op}' must take exactly {operands} operand(s)"),
+                    Token::RParen,
+                    proto.span,
+                )));
+            }
+        }
+
+        Ok(proto)
+    }
 
-        let mut func_args: Vec<String> = Vec::new();
+    /// list(item)
+    ///     ::= (item (',' item)* ','?)?
+    ///
+    /// Parses the items up to and including the closing ')', after the
+    /// caller has eaten the '('. Returns them with the span of the ')'.
+    fn parse_list<T>(&mut self, what: &str, mut parse_item: impl FnMut(&mut Self) -> ParseResult<T>)
+                     -> ParseResult<(Vec<T>, Span)> {
+        let mut items = Vec::new();
         loop {
-            let Token::Identifier { id: arg } = self.lexer.last_token() else {
-                return self.log_error("Expected identifier in prototype arguments");
-            };
+            if self.lexer.last_token() == Token::RParen {
+                let end = self.lexer.last_span();
+                self.lexer.next(); // eat ')'
+                return Ok((items, end));
+            }
+
+            items.push(parse_item(self)?);
 
-            if arg == String::from(")") {
-                break;
+            match self.lexer.last_token() {
+                Token::RParen => {},
+                Token::Comma => self.lexer.next(),
+                _ => return self.error(&format!("Expected ')' or ',' in {what}"), &["')'", "','"]),
             }
-            
-            func_args.push(arg);
+        }
+    }
 
-            self.lexer.next(); // eat identifier
+    /// The operator following `unary` or `binary`, which must not be built in.
+    fn parse_operator_name(&mut self, keyword: &str, is_builtin: impl Fn(&str) -> bool) -> ParseResult<String> {
+        let Token::Operator { op } = self.lexer.last_token() else {
+            return self.error(&format!("Expected operator after '{keyword}'"), &["operator"]);
+        };
+        if is_builtin(&op) {
+            return self.invalid_operator(&format!("Cannot redefine built-in {keyword} operator '{op}'"));
         }
+        self.lexer.next(); // eat operator
+        Ok(op)
+    }
 
-        return Some(Box::new(ExprAST::PrototypeAST { name: func_name, args: func_args }));
+    /// The optional precedence of a binary operator.
+    fn parse_operator_precedence(&mut self) -> ParseResult<i32> {
+        let Token::Number { value } = self.lexer.last_token() else {
+            return Ok(DEFAULT_OPERATOR_PRECEDENCE);
+        };
+        if value.fract() != 0.0 || !OPERATOR_PRECEDENCE_RANGE.contains(&(value as i32)) {
+            return self.invalid_operator(&format!(
+                "Operator precedence must be a whole number from {} to {}",
+                OPERATOR_PRECEDENCE_RANGE.start(),
+                OPERATOR_PRECEDENCE_RANGE.end(),
+            ));
+        }
+        self.lexer.next(); // eat precedence
+        Ok(value as i32)
     }
 
     fn get_precedence(&self, tok: &Token) -> i32 {
-        if let Token::Identifier { id } = tok {
-            if self.prec.contains_key(id) {
-                return self.prec[id];
+        if let Token::Operator { op } = tok {
+            if let Some(info) = self.prec.get(op) {
+                return info.precedence;
             }
         }
-        return -1;
+        -1
+    }
+
+    fn enter(&mut self, rule: &'static str) {
+        if let Some(sink) = &mut self.trace {
+            sink.event(&TraceEvent::EnterRule {
+                rule,
+                token: self.lexer.last_token(),
+                span: self.lexer.last_span(),
+            });
+        }
     }
 
-    fn log_error(&self, s: &str) -> Option<Box<ExprAST>> {
-        eprintln!("[***] Error: {s}");
-        None
+    /// Builds an `InvalidOperator` error located at the current token.
+    fn invalid_operator<T>(&self, message: &str) -> ParseResult<T> {
+        Err(Box::new(ParseError::with_kind(
+            ParseErrorKind::InvalidOperator,
+            message,
+            self.lexer.last_token(),
+            self.lexer.last_span(),
+        )))
     }
 
-    fn log_error_p(&self, s: &str) -> Option<Box<ExprAST>> {
-        eprintln!("[***] Error: {s}"); 
-        None
+    /// Builds an error located at the current token.
+    fn error<T>(&self, message: &str, expected: &[&str]) -> ParseResult<T> {
+        Err(Box::new(ParseError::new(message, expected, self.lexer.last_token(), self.lexer.last_span())))
     }
 }
-
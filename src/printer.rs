@@ -0,0 +1,229 @@
+use crate::parser::{
+    default_operators, Associativity, ExprAST, FunctionAST, Item, OperatorInfo, OperatorTable, PrototypeAST,
+    PrototypeKind, VarBinding, ASSIGN_OPERATOR, BINARY_PREFIX, UNARY_PREFIX,
+};
+use std::fmt;
+
+/// Prints an expression or item as Kaleidoscope source using a given
+/// operator table, see `ExprAST::display_with` and `Item::display_with`.
+pub struct WithPrecedence<'a, T> {
+    node: &'a T,
+    prec: &'a OperatorTable,
+}
+
+impl ExprAST {
+    /// Prints `self` with only the parentheses needed under `prec`, e.g.
+    /// a table that includes user-defined operators.
+    pub fn display_with<'a>(&'a self, prec: &'a OperatorTable) -> WithPrecedence<'a, ExprAST> {
+        WithPrecedence { node: self, prec }
+    }
+}
+
+impl Item {
+    /// Prints `self` with the expressions formatted as by
+    /// `ExprAST::display_with`.
+    pub fn display_with<'a>(&'a self, prec: &'a OperatorTable) -> WithPrecedence<'a, Item> {
+        WithPrecedence { node: self, prec }
+    }
+}
+
+impl fmt::Display for WithPrecedence<'_, ExprAST> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_expr(f, self.node, self.prec)
+    }
+}
+
+impl fmt::Display for WithPrecedence<'_, Item> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.node {
+            Item::Extern(proto) => write!(f, "extern {proto}"),
+            Item::Definition(function) => write!(f, "def {} {}", function.proto, function.body.display_with(self.prec)),
+            Item::TopLevelExpr(function) => write!(f, "{}", function.body.display_with(self.prec)),
+        }
+    }
+}
+
+/// Prints the expression with the minimal parentheses for the default
+/// operator table. Parsing the output yields a tree equal to `self`
+/// (ignoring spans) for every tree the parser can produce, except that
+/// infinite numbers such as `1e999` come back as `1 / 0`.
+impl fmt::Display for ExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_expr(f, self, &default_operators())
+    }
+}
+
+impl fmt::Display for PrototypeAST {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.operator(), self.kind) {
+            (Some(op), PrototypeKind::UnaryOp) => write!(f, "{UNARY_PREFIX}{op} ({})", self.args.join(", ")),
+            (Some(op), PrototypeKind::BinaryOp { precedence }) => {
+                write!(f, "{BINARY_PREFIX}{op} {precedence} ({})", self.args.join(", "))
+            },
+            _ => write!(f, "{}({})", self.name, self.args.join(", ")),
+        }
+    }
+}
+
+impl fmt::Display for FunctionAST {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "def {} {}", self.proto, self.body)
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.display_with(&default_operators()))
+    }
+}
+
+fn write_expr(f: &mut fmt::Formatter<'_>, expr: &ExprAST, prec: &OperatorTable) -> fmt::Result {
+    match expr {
+        ExprAST::NumberExprAST { val, .. } => write_number(f, *val),
+        ExprAST::VariableExprAST { name, .. } => write!(f, "{name}"),
+        ExprAST::UnaryExprAST { op, operand, .. } => {
+            // Anything but an atom is parenthesized: a bare binary expression
+            // would bind looser than the operator, and nested prefix
+            // operators could lex as one, e.g. `!` and `=` as `!=`. So is a
+            // number after `.`, which would lex as part of it.
+            write!(f, "{op}")?;
+            match **operand {
+                ExprAST::NumberExprAST { .. } if op.ends_with('.') => {
+                    write!(f, "(")?;
+                    write_expr(f, operand, prec)?;
+                    write!(f, ")")
+                },
+                ExprAST::NumberExprAST { .. }
+                | ExprAST::VariableExprAST { .. }
+                | ExprAST::CallExprAST { .. }
+                | ExprAST::BlockExprAST { .. } => {
+                    write_expr(f, operand, prec)
+                },
+                _ => {
+                    write!(f, "(")?;
+                    write_expr(f, operand, prec)?;
+                    write!(f, ")")
+                },
+            }
+        },
+        ExprAST::BinaryExprAST { op, lhs, rhs, .. } | ExprAST::LogicalExprAST { op, lhs, rhs, .. } => {
+            // An operand of the same precedence goes without parentheses
+            // only on the side the operators group towards.
+            let info = operator(op, prec);
+            write_operand(f, lhs, prec, |lhs| {
+                lhs.precedence < info.precedence
+                    || lhs.precedence == info.precedence
+                        && !(info.associativity == Associativity::Left && lhs.associativity == Associativity::Left)
+            })?;
+            write!(f, " {op} ")?;
+            write_operand(f, rhs, prec, |rhs| {
+                rhs.precedence < info.precedence
+                    || rhs.precedence == info.precedence
+                        && !(info.associativity == Associativity::Right && rhs.associativity != Associativity::NonAssoc)
+            })
+        },
+        ExprAST::CallExprAST { callee, args, .. } => {
+            write!(f, "{callee}(")?;
+            for (idx, arg) in args.iter().enumerate() {
+                if idx > 0 {
+                    write!(f, ", ")?;
+                }
+                write_expr(f, arg, prec)?;
+            }
+            write!(f, ")")
+        },
+        ExprAST::IfExprAST { cond, then, else_, .. } => {
+            write!(f, "if ")?;
+            write_expr(f, cond, prec)?;
+            write!(f, " then ")?;
+            write_expr(f, then, prec)?;
+            write!(f, " else ")?;
+            write_expr(f, else_, prec)
+        },
+        ExprAST::VarExprAST { vars, body, .. } => {
+            write!(f, "var ")?;
+            for (idx, VarBinding { name, init, .. }) in vars.iter().enumerate() {
+                if idx > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{name}")?;
+                if let Some(init) = init {
+                    write!(f, " = ")?;
+                    write_expr(f, init, prec)?;
+                }
+            }
+            write!(f, " in ")?;
+            write_expr(f, body, prec)
+        },
+        ExprAST::BlockExprAST { exprs, .. } if exprs.is_empty() => write!(f, "{{}}"),
+        ExprAST::BlockExprAST { exprs, .. } => {
+            write!(f, "{{ ")?;
+            for (idx, expr) in exprs.iter().enumerate() {
+                if idx > 0 {
+                    write!(f, "; ")?;
+                }
+                write_expr(f, expr, prec)?;
+            }
+            write!(f, " }}")
+        },
+        ExprAST::AssignExprAST { name, value, .. } => {
+            let info = operator(ASSIGN_OPERATOR, prec);
+            write!(f, "{name} = ")?;
+            write_operand(f, value, prec, |value| value.precedence < info.precedence)
+        },
+        ExprAST::ForExprAST { var, start, end, step, body, .. } => {
+            write!(f, "for {var} = ")?;
+            write_expr(f, start, prec)?;
+            write!(f, ", ")?;
+            write_expr(f, end, prec)?;
+            if let Some(step) = step {
+                write!(f, ", ")?;
+                write_expr(f, step, prec)?;
+            }
+            write!(f, " in ")?;
+            write_expr(f, body, prec)
+        },
+    }
+}
+
+/// Writes an operand of a binary operator, parenthesized if `needs_parens`
+/// says so for its operator. `if`, `for` and `var` extend as far to the right
+/// as possible, so they are always parenthesized.
+fn write_operand(f: &mut fmt::Formatter<'_>, operand: &ExprAST, prec: &OperatorTable,
+                 needs_parens: impl Fn(OperatorInfo) -> bool) -> fmt::Result {
+    let parens = match operand {
+        ExprAST::BinaryExprAST { op, .. } | ExprAST::LogicalExprAST { op, .. } => {
+            prec.get(op).is_none_or(|info| needs_parens(*info))
+        },
+        ExprAST::AssignExprAST { .. } => needs_parens(operator(ASSIGN_OPERATOR, prec)),
+        ExprAST::IfExprAST { .. } | ExprAST::ForExprAST { .. } | ExprAST::VarExprAST { .. } => true,
+        _ => false,
+    };
+    if parens {
+        write!(f, "(")?;
+        write_expr(f, operand, prec)?;
+        write!(f, ")")
+    } else {
+        write_expr(f, operand, prec)
+    }
+}
+
+/// Writes `val` so that it reads back as the same number. There are no
+/// literals for infinities and NaN, so they are written as divisions.
+fn write_number(f: &mut fmt::Formatter<'_>, val: f64) -> fmt::Result {
+    if val.is_nan() {
+        write!(f, "(0 / 0)")
+    } else if val.is_infinite() {
+        write!(f, "({}1 / 0)", if val < 0.0 { "-" } else { "" })
+    } else {
+        write!(f, "{val}")
+    }
+}
+
+/// The precedence of an operator missing from the table isn't known, so
+/// it is parenthesized on both sides: its own operands are parenthesized
+/// by treating it as binding tightest here, and `write_operand` puts it in
+/// parentheses wherever it is an operand.
+fn operator(op: &str, prec: &OperatorTable) -> OperatorInfo {
+    prec.get(op).copied().unwrap_or(OperatorInfo::new(i32::MAX, Associativity::NonAssoc))
+}
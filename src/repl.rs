@@ -0,0 +1,163 @@
+use crate::error::ParseErrorKind;
+use crate::eval::Interpreter;
+use crate::opt;
+use crate::parser::{default_operators, parse_source_with_operators, OperatorTable, Program};
+use crate::sema::{self, Analyzer};
+use std::io::{self, BufRead, Write};
+
+const PROMPT: &str = "ready> ";
+const CONTINUATION_PROMPT: &str = "...> ";
+
+const HELP: &str = "\
+Enter definitions (`def`), externs (`extern`) or expressions to evaluate.
+Commands:
+  :quit          leave the REPL
+  :load FILE     parse and run a .ks file
+  :ast           toggle printing the AST of each input
+  :cancel        discard unfinished input
+  :help          show this message
+";
+
+/// Read-eval-print loop: reads definitions and expressions, keeps
+/// definitions around for later input and prints the value of every
+/// top-level expression.
+pub struct Repl<W: Write> {
+    interpreter: Interpreter,
+    /// Knows the functions defined so far, to check new input against.
+    analyzer: Analyzer,
+    /// Binary operators, including operators defined in earlier input.
+    prec: OperatorTable,
+    output: W,
+    show_ast: bool,
+}
+
+impl<W: Write> Repl<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            interpreter: Interpreter::new(),
+            analyzer: Analyzer::new(),
+            prec: default_operators(),
+            output,
+            show_ast: false,
+        }
+    }
+
+    /// See `Interpreter::with_max_call_depth`.
+    pub fn with_max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.interpreter = std::mem::take(&mut self.interpreter).with_max_call_depth(max_call_depth);
+        self
+    }
+
+    /// Runs until `:quit` or the end of `input`. Input that ends in the
+    /// middle of a construct is continued on the next line; commands work
+    /// there too, and `:cancel` discards it.
+    pub fn run(&mut self, input: impl BufRead) -> io::Result<()> {
+        let mut lines = input.lines();
+        let mut pending = String::new();
+        loop {
+            write!(self.output, "{}", if pending.is_empty() { PROMPT } else { CONTINUATION_PROMPT })?;
+            self.output.flush()?;
+
+            let Some(line) = lines.next() else {
+                writeln!(self.output)?;
+                return Ok(());
+            };
+            let line = line?;
+
+            if line.trim_start().starts_with(':') {
+                if !self.handle_command(line.trim(), &mut pending)? {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            pending.push_str(&line);
+            pending.push('\n');
+            if pending.trim().is_empty() {
+                pending.clear();
+                continue;
+            }
+
+            // parse against a copy so that incomplete or rejected input doesn't define operators
+            let mut prec = self.prec.clone();
+            let program = parse_source_with_operators(pending.as_bytes(), &mut prec);
+            if is_incomplete(&program) {
+                continue;
+            }
+            self.execute(program, &pending, prec)?;
+            pending.clear();
+        }
+    }
+
+    /// Handles a `:command`, possibly entered while the input in `pending`
+    /// is unfinished. Returns `false` when the REPL should stop.
+    fn handle_command(&mut self, line: &str, pending: &mut String) -> io::Result<bool> {
+        let (command, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let argument = argument.trim();
+        match command {
+            ":quit" | ":q" => return Ok(false),
+            ":help" => write!(self.output, "{HELP}")?,
+            ":cancel" => pending.clear(),
+            ":ast" => {
+                self.show_ast = !self.show_ast;
+                writeln!(self.output, "AST printing {}", if self.show_ast { "on" } else { "off" })?;
+            },
+            ":load" if argument.is_empty() => writeln!(self.output, "error: usage: :load FILE")?,
+            ":load" => match std::fs::read_to_string(argument) {
+                Ok(source) => {
+                    let mut prec = self.prec.clone();
+                    let program = parse_source_with_operators(source.as_bytes(), &mut prec);
+                    self.execute(program, &source, prec)?;
+                },
+                Err(err) => writeln!(self.output, "error: cannot read '{argument}': {err}")?,
+            },
+            _ => writeln!(self.output, "error: unknown command '{command}', try :help")?,
+        }
+        Ok(true)
+    }
+
+    /// Reports parse errors and semantic errors, or evaluates the items of
+    /// optimized `program` in order. `prec` is the operator table after
+    /// parsing `program`; it is kept only if there are no errors, so that a
+    /// rejected definition doesn't leave its operator behind.
+    fn execute(&mut self, mut program: Program, source: &str, prec: OperatorTable) -> io::Result<()> {
+        if !program.is_ok() {
+            for err in &program.errors {
+                write!(self.output, "{}", err.render(source))?;
+            }
+            return Ok(());
+        }
+        if self.show_ast {
+            for item in &program.items {
+                writeln!(self.output, "{};", item.display_with(&prec))?;
+            }
+        }
+
+        let diagnostics = self.analyzer.analyze(&program);
+        for diagnostic in &diagnostics {
+            write!(self.output, "{}", diagnostic.render(source))?;
+        }
+        if sema::has_errors(&diagnostics) {
+            return Ok(());
+        }
+        self.prec = prec;
+        opt::optimize(&mut program);
+
+        let exprs = match self.interpreter.declare(program) {
+            Ok(exprs) => exprs,
+            Err(err) => return writeln!(self.output, "error: {err}"),
+        };
+        for expr in exprs {
+            match self.interpreter.eval(&expr) {
+                Ok(value) => writeln!(self.output, "{value}")?,
+                Err(err) => return writeln!(self.output, "error: {err}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Input is incomplete when it parses fine up to an unexpected end of input.
+fn is_incomplete(program: &Program) -> bool {
+    program.errors.len() == 1 && program.errors[0].kind == ParseErrorKind::UnexpectedEof
+}
@@ -0,0 +1,285 @@
+use crate::error::render_diagnostic;
+use crate::eval::native_arity;
+use crate::lexer::Span;
+use crate::parser::{
+    default_operators, ExprAST, FunctionAST, Item, OperatorTable, Program, VarBinding, BINARY_PREFIX,
+    BUILTIN_UNARY_OPERATORS, UNARY_PREFIX,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    /// Reported, but the program still runs.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    UndefinedFunction,
+    UndefinedVariable,
+    /// A user-defined operator without a `unary` or `binary` definition.
+    UndefinedOperator,
+    /// An `extern` for which the interpreter has no native implementation.
+    UnknownExtern,
+    ArityMismatch,
+    /// A second declaration of a name in the same program. Redeclaring an
+    /// `extern` with the same parameter count is allowed.
+    DuplicateDefinition,
+    /// A `var` or `for` variable hiding a parameter or another variable.
+    Shadowing,
+}
+
+impl DiagnosticKind {
+    pub fn severity(self) -> Severity {
+        match self {
+            DiagnosticKind::Shadowing => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+/// A problem found by `Analyzer`, located in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        self.kind.severity()
+    }
+
+    /// Formats the diagnostic together with the offending line of `source`.
+    pub fn render(&self, source: &str) -> String {
+        render_diagnostic(&self.severity().to_string(), &self.message, self.span, source)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity(), self.message)
+    }
+}
+
+/// Whether any of `diagnostics` should stop the program from running.
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|diagnostic| diagnostic.severity() == Severity::Error)
+}
+
+/// Checks a single program on its own, see `Analyzer::analyze`.
+pub fn analyze(program: &Program) -> Vec<Diagnostic> {
+    Analyzer::new().analyze(program)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Symbol {
+    arity: usize,
+    /// Index of the declaring item in the program being analyzed, or
+    /// `None` for declarations from earlier programs.
+    item: Option<usize>,
+}
+
+/// Name resolution and arity checking. The analyzer remembers the
+/// functions of every program that passed, so that later input (e.g. the
+/// next line at the REPL) can call them.
+#[derive(Debug, Default)]
+pub struct Analyzer {
+    symbols: HashMap<String, Symbol>,
+}
+
+impl Analyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks that every call, variable and operator in `program` refers to
+    /// something declared, with the right number of arguments, and that
+    /// every `extern` has a native implementation.
+    ///
+    /// Functions may be called from anywhere in the program, also above
+    /// their definition, since `Interpreter::declare` registers all of them
+    /// before the first top-level expression runs.
+    pub fn analyze(&mut self, program: &Program) -> Vec<Diagnostic> {
+        let mut symbols = self.symbols.clone();
+        let mut diagnostics = Vec::new();
+        for (idx, item) in program.items.iter().enumerate() {
+            let (Item::Extern(proto) | Item::Definition(FunctionAST { proto, .. })) = item else {
+                continue;
+            };
+            let is_extern = matches!(item, Item::Extern(_));
+            let arity = proto.args.len();
+            if is_extern {
+                check_extern(&proto.name, arity, proto.span, &mut diagnostics);
+            }
+            match symbols.get(&proto.name) {
+                Some(&Symbol { item: Some(earlier), arity: earlier_arity }) => {
+                    let both_externs = is_extern && matches!(program.items[earlier], Item::Extern(_));
+                    if !both_externs || earlier_arity != arity {
+                        diagnostics.push(Diagnostic {
+                            kind: DiagnosticKind::DuplicateDefinition,
+                            message: format!("'{}' is already declared", proto.name),
+                            span: proto.span,
+                        });
+                    }
+                },
+                _ => {
+                    symbols.insert(proto.name.clone(), Symbol { arity, item: Some(idx) });
+                },
+            }
+        }
+
+        let operators = default_operators();
+        for item in &program.items {
+            let (Item::Definition(function) | Item::TopLevelExpr(function)) = item else {
+                continue;
+            };
+            let mut checker = Checker {
+                symbols: &symbols,
+                operators: &operators,
+                scope: function.proto.args.clone(),
+                diagnostics: &mut diagnostics,
+            };
+            checker.check(&function.body);
+        }
+
+        if !has_errors(&diagnostics) {
+            self.symbols = symbols.into_iter()
+                .map(|(name, symbol)| (name, Symbol { item: None, ..symbol }))
+                .collect();
+        }
+        diagnostics
+    }
+}
+
+fn check_extern(name: &str, arity: usize, span: Span, diagnostics: &mut Vec<Diagnostic>) {
+    let (kind, message) = match native_arity(name) {
+        None => (DiagnosticKind::UnknownExtern, format!("No native implementation for extern '{name}'")),
+        Some(native) if native != arity => (
+            DiagnosticKind::ArityMismatch,
+            format!("Native function '{name}' takes {native} argument(s) but is declared with {arity}"),
+        ),
+        Some(_) => return,
+    };
+    diagnostics.push(Diagnostic { kind, message, span });
+}
+
+/// Walks one function body.
+struct Checker<'a> {
+    symbols: &'a HashMap<String, Symbol>,
+    operators: &'a OperatorTable,
+    /// Variables in scope, innermost last.
+    scope: Vec<String>,
+    diagnostics: &'a mut Vec<Diagnostic>,
+}
+
+impl Checker<'_> {
+    fn check(&mut self, expr: &ExprAST) {
+        match expr {
+            ExprAST::NumberExprAST { .. } => {},
+            ExprAST::VariableExprAST { name, span } => self.check_variable(name, *span),
+            ExprAST::UnaryExprAST { op, operand, span } => {
+                self.check(operand);
+                if !BUILTIN_UNARY_OPERATORS.contains(&op.as_str()) {
+                    self.check_operator(UNARY_PREFIX, op, *span);
+                }
+            },
+            ExprAST::BinaryExprAST { op, lhs, rhs, span } => {
+                self.check(lhs);
+                self.check(rhs);
+                if !self.operators.contains_key(op) {
+                    self.check_operator(BINARY_PREFIX, op, *span);
+                }
+            },
+            ExprAST::LogicalExprAST { lhs, rhs, .. } => {
+                self.check(lhs);
+                self.check(rhs);
+            },
+            ExprAST::CallExprAST { callee, args, span } => {
+                for arg in args {
+                    self.check(arg);
+                }
+                match self.symbols.get(callee) {
+                    None => self.report(DiagnosticKind::UndefinedFunction, format!("Unknown function '{callee}'"), *span),
+                    Some(symbol) if symbol.arity != args.len() => self.report(
+                        DiagnosticKind::ArityMismatch,
+                        format!("Function '{callee}' takes {} argument(s) but {} were supplied", symbol.arity, args.len()),
+                        *span,
+                    ),
+                    Some(_) => {},
+                }
+            },
+            ExprAST::IfExprAST { cond, then, else_, .. } => {
+                self.check(cond);
+                self.check(then);
+                self.check(else_);
+            },
+            ExprAST::ForExprAST { var, var_span, start, end, step, body, .. } => {
+                self.check(start);
+                let outer = self.scope.len();
+                self.bind(var, *var_span);
+                self.check(end);
+                if let Some(step) = step {
+                    self.check(step);
+                }
+                self.check(body);
+                self.scope.truncate(outer);
+            },
+            ExprAST::VarExprAST { vars, body, .. } => {
+                let outer = self.scope.len();
+                for VarBinding { name, init, span } in vars {
+                    if let Some(init) = init {
+                        self.check(init);
+                    }
+                    self.bind(name, *span);
+                }
+                self.check(body);
+                self.scope.truncate(outer);
+            },
+            ExprAST::AssignExprAST { name, value, span } => {
+                self.check(value);
+                self.check_variable(name, *span);
+            },
+            ExprAST::BlockExprAST { exprs, .. } => {
+                for expr in exprs {
+                    self.check(expr);
+                }
+            },
+        }
+    }
+
+    fn check_variable(&mut self, name: &str, span: Span) {
+        if !self.scope.iter().any(|var| var == name) {
+            self.report(DiagnosticKind::UndefinedVariable, format!("Unknown variable '{name}'"), span);
+        }
+    }
+
+    fn check_operator(&mut self, prefix: &str, op: &str, span: Span) {
+        if !self.symbols.contains_key(&format!("{prefix}{op}")) {
+            self.report(DiagnosticKind::UndefinedOperator, format!("Unknown operator '{op}'"), span);
+        }
+    }
+
+    fn bind(&mut self, name: &str, span: Span) {
+        if self.scope.iter().any(|var| var == name) {
+            self.report(DiagnosticKind::Shadowing, format!("'{name}' shadows an outer variable of the same name"), span);
+        }
+        self.scope.push(name.to_string());
+    }
+
+    fn report(&mut self, kind: DiagnosticKind, message: String, span: Span) {
+        self.diagnostics.push(Diagnostic { kind, message, span });
+    }
+}
@@ -1,13 +1,18 @@
-use crate::lexer::{Tokenizer, Token,};
-use crate::parser::{Parser, ExprAST,};
+use crate::lexer::{Tokenizer, Token, Position, Span, LexError};
+use crate::parser::{
+    parse_source, Parser, ExprAST, Item, PrototypeKind, VarBinding, ANON_FUNCTION_NAME, DEFAULT_OPERATOR_PRECEDENCE,
+};
+use crate::error::ParseErrorKind;
 
 use std::io::BufReader;
 
 #[cfg(test)]
 mod test_frontend {
-    use std::env::args;
-
     use super::*;
+    use crate::trace::{TraceEvent, TraceLog};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
     #[test]
     pub fn test_read() {
         let mut bufreader = BufReader::new(
@@ -19,12 +24,12 @@ mod test_frontend {
                         fib(x - 1) + fib(x - 2)
             "#.as_bytes());
         let mut tokenizer = Tokenizer::new(&mut bufreader);
-        let expected = [Token::Def, id_tok("fib"), id_tok("("), id_tok("x"), id_tok(")"),
-                id_tok("if"), id_tok("x"), id_tok("<"), num_tok(3.0), id_tok("then"),
+        let expected = [Token::Def, id_tok("fib"), Token::LParen, id_tok("x"), Token::RParen,
+                Token::If, id_tok("x"), op_tok("<"), num_tok(3.0), Token::Then,
                 num_tok(1.0),
-                id_tok("else"),
-                id_tok("fib"), id_tok("("), id_tok("x"), id_tok("-"), num_tok(1.0), id_tok(")"),
-                    id_tok("+"), id_tok("fib"), id_tok("("), id_tok("x"), id_tok("-"), num_tok(2.0), id_tok(")"),
+                Token::Else,
+                id_tok("fib"), Token::LParen, id_tok("x"), op_tok("-"), num_tok(1.0), Token::RParen,
+                    op_tok("+"), id_tok("fib"), Token::LParen, id_tok("x"), op_tok("-"), num_tok(2.0), Token::RParen,
                 Token::Eof
                 ];
 
@@ -37,156 +42,1352 @@ mod test_frontend {
     }
 
     #[test]
-    pub fn test_ast_single() {
-        {
-            let inp = "5";
-            let expected_ast = Box::new(ExprAST::NumberExprAST { val: 5 as f64 });
-            println!("comparing ast");
-            test_input_ast(inp, Some(expected_ast));
-            println!("ast compared");
+    pub fn test_read_operators() {
+        let mut bufreader = BufReader::new("a<=b == c != d->e <f, (g)! >= = && ||".as_bytes());
+        let mut tokenizer = Tokenizer::new(&mut bufreader);
+        let expected = [id_tok("a"), op_tok("<="), id_tok("b"), op_tok("=="), id_tok("c"),
+                op_tok("!="), id_tok("d"), op_tok("->"), id_tok("e"), op_tok("<"), id_tok("f"),
+                Token::Comma, Token::LParen, id_tok("g"), Token::RParen, op_tok("!"), op_tok(">="),
+                op_tok("="), op_tok("&&"), op_tok("||"),
+                Token::Eof
+                ];
+
+        for exp in expected {
+            assert_eq!(exp, tokenizer.last_token());
+            tokenizer.next();
         }
+    }
+
+    #[test]
+    pub fn test_read_unicode() {
+        let source = "# café ☕\nrésumé + 𝑥 * _tmp1 → über".as_bytes();
+        let mut bufreader = BufReader::new(source);
+        let mut tokenizer = Tokenizer::new(&mut bufreader);
+        let expected = [id_tok("résumé"), op_tok("+"), id_tok("𝑥"), op_tok("*"), id_tok("_tmp1"),
+                op_tok("→"), id_tok("über"), Token::Eof];
 
-        {
-            let inp = "var";
-            let expected_ast = Box::new(ExprAST::VariableExprAST { name: String::from("var") });
-            test_input_ast(inp, Some(expected_ast));
+        for exp in expected {
+            assert_eq!(exp, tokenizer.last_token());
+            tokenizer.next();
         }
+
+        let mut bufreader = BufReader::new("é x".as_bytes());
+        let mut tokenizer = Tokenizer::new(&mut bufreader);
+        tokenizer.next();
+        let span = tokenizer.last_span();
+        assert_eq!((3, 1, 3), (span.start.offset, span.start.line, span.start.column));
+    }
+
+    #[test]
+    pub fn test_read_invalid_utf8() {
+        let source: &[u8] = b"ab \xFF cd \xE2\x82 ef";
+        let mut bufreader = BufReader::new(source);
+        let mut tokenizer = Tokenizer::new(&mut bufreader);
+        let invalid = Token::Error { error: LexError::InvalidUtf8 };
+
+        assert_eq!(id_tok("ab"), tokenizer.last_token());
+        tokenizer.next();
+        assert_eq!(invalid, tokenizer.last_token());
+        assert_eq!(3, tokenizer.last_span().start.offset);
+        assert_eq!(4, tokenizer.last_span().start.column);
+        tokenizer.next();
+        assert_eq!(id_tok("cd"), tokenizer.last_token());
+        tokenizer.next();
+        assert_eq!(invalid, tokenizer.last_token());
+        assert_eq!(8, tokenizer.last_span().start.offset);
+        tokenizer.next();
+        assert_eq!(id_tok("ef"), tokenizer.last_token());
+        tokenizer.next();
+        assert_eq!(Token::Eof, tokenizer.last_token());
+
+        let mut bufreader = BufReader::new(source);
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let program = parser.parse();
+        assert_eq!(ParseErrorKind::Lexical, program.errors[0].kind);
+        assert_eq!(1, program.errors[0].span.start.line);
+        assert_eq!(4, program.errors[0].span.start.column);
+    }
+
+    #[test]
+    pub fn test_read_numbers() {
+        let mut bufreader = BufReader::new(
+            "42 3.25 1. .5 1e-9 2.5E+3 7e2 0x1F 0XfF 0o17 0b1010 1_000_000 0x_1 1.2.3 1e 0x 0b102 1__0 1_ 2x".as_bytes());
+        let mut tokenizer = Tokenizer::new(&mut bufreader);
+        let malformed = |s: &str| Token::Error { error: LexError::MalformedNumber(String::from(s)) };
+        let expected = [num_tok(42.0), num_tok(3.25), num_tok(1.0), num_tok(0.5), num_tok(1e-9),
+                num_tok(2500.0), num_tok(700.0), num_tok(31.0), num_tok(255.0), num_tok(15.0),
+                num_tok(10.0), num_tok(1_000_000.0), malformed("0x_1"), malformed("1.2.3"),
+                malformed("1e"), malformed("0x"), malformed("0b102"), malformed("1__0"),
+                malformed("1_"), malformed("2x"), Token::Eof];
+
+        for exp in expected {
+            assert_eq!(exp, tokenizer.last_token());
+            tokenizer.next();
+        }
+
+        let mut bufreader = BufReader::new("x-1 . y 1.2.3 + 4".as_bytes());
+        let mut tokenizer = Tokenizer::new(&mut bufreader);
+        let expected = [id_tok("x"), op_tok("-"), num_tok(1.0), op_tok("."), id_tok("y"),
+                malformed("1.2.3"), op_tok("+"), num_tok(4.0), Token::Eof];
+
+        for exp in expected {
+            assert_eq!(exp, tokenizer.last_token());
+            tokenizer.next();
+        }
+    }
+
+    #[test]
+    pub fn test_spans() {
+        let mut bufreader = BufReader::new("def foo(x)\n  # comment\n  x + 42".as_bytes());
+        let mut tokenizer = Tokenizer::new(&mut bufreader);
+        let pos = |offset, line, column| Position { offset, line, column };
+        let expected = [
+            (Token::Def, Span::new(pos(0, 1, 1), pos(3, 1, 4))),
+            (id_tok("foo"), Span::new(pos(4, 1, 5), pos(7, 1, 8))),
+            (Token::LParen, Span::new(pos(7, 1, 8), pos(8, 1, 9))),
+            (id_tok("x"), Span::new(pos(8, 1, 9), pos(9, 1, 10))),
+            (Token::RParen, Span::new(pos(9, 1, 10), pos(10, 1, 11))),
+            (id_tok("x"), Span::new(pos(25, 3, 3), pos(26, 3, 4))),
+            (op_tok("+"), Span::new(pos(27, 3, 5), pos(28, 3, 6))),
+            (num_tok(42.0), Span::new(pos(29, 3, 7), pos(31, 3, 9))),
+            (Token::Eof, Span::new(pos(31, 3, 9), pos(31, 3, 9))),
+        ];
+
+        for (exp_token, exp_span) in expected {
+            assert_eq!(exp_token, tokenizer.last_token());
+            assert_eq!(exp_span, tokenizer.last_span());
+            tokenizer.next();
+        }
+    }
+
+    #[test]
+    pub fn test_ast_single() {
+        test_input_ast("5", Some(num(5.0)));
+        test_input_ast("value", Some(var("value")));
     }
 
     #[test]
     pub fn test_ast_simple() {
-        let num = "5 + 6";
-        let expected_ast = Box::new(
-            ExprAST::BinaryExprAST { 
-                op: String::from("+"),
-                lhs: Box::new(ExprAST::NumberExprAST { val: 5 as f64 }),
-                rhs: Box::new(ExprAST::NumberExprAST { val: 6 as f64 }),
-            }
-        );
-        test_input_ast(num, Some(expected_ast));
+        test_input_ast("5 + 6", Some(bin("+", num(5.0), num(6.0))));
     }
 
 
     #[test]
     pub fn test_ast_long_arithmetic() {
         let long_arithmetic = "5 + (6 + 7) * 8";
-        let expected_ast = Box::new(
-            ExprAST::BinaryExprAST {
-                op: String::from("+"),
-                lhs: Box::new(ExprAST::NumberExprAST { val: 5_f64 }),
-                rhs: Box::new(ExprAST::BinaryExprAST { 
-                    op: String::from("*"), 
-                    lhs: Box::new(ExprAST::BinaryExprAST { 
-                        op: String::from("+"), 
-                        lhs: Box::new(ExprAST::NumberExprAST { val: 6_f64 }), 
-                        rhs: Box::new(ExprAST::NumberExprAST { val: 7_f64 }) 
-                    }),
-                    rhs: Box::new(ExprAST::NumberExprAST { val: 8_f64 })
-                })
-            }
-        );
+        let expected_ast = bin("+", num(5.0), bin("*", bin("+", num(6.0), num(7.0)), num(8.0)));
         test_input_ast(long_arithmetic, Some(expected_ast));
     }
 
     #[test]
     pub fn test_ast_with_variables() {
-        let long_arithmetic = "5 + (var + 7) * k";
-        let expected_ast = Box::new(
-            ExprAST::BinaryExprAST {
-                op: String::from("+"),
-                lhs: Box::new(ExprAST::NumberExprAST { val: 5_f64 }),
-                rhs: Box::new(ExprAST::BinaryExprAST { 
-                    op: String::from("*"), 
-                    lhs: Box::new(ExprAST::BinaryExprAST { 
-                        op: String::from("+"), 
-                        lhs: Box::new(ExprAST::VariableExprAST { name: String::from("var") }), 
-                        rhs: Box::new(ExprAST::NumberExprAST { val: 7_f64 }) 
-                    }),
-                    rhs: Box::new(ExprAST::VariableExprAST { name: String::from("k") })
-                })
-            }
-        );
+        let long_arithmetic = "5 + (value + 7) * k";
+        let expected_ast = bin("+", num(5.0), bin("*", bin("+", var("value"), num(7.0)), var("k")));
         test_input_ast(long_arithmetic, Some(expected_ast));
     }
 
-    fn test_input_ast<'a>(input: &str, expected_ast: Option<Box<ExprAST>>) {
-        let mut bufreader = BufReader::new(input.as_bytes());
-        println!("creating tokenizer");
+    #[test]
+    pub fn test_parse_error() {
+        let source = "(1 + x\n";
+        let mut bufreader = BufReader::new(source.as_bytes());
         let mut lexer = Tokenizer::new(&mut bufreader);
-        println!("creating parser");
         let mut parser = Parser::new(&mut lexer);
-        println!("calling parser");
-        let ast_result_opt = parser.parse_expression();
-        assert!(compare_ast_opts(ast_result_opt, expected_ast));
+        let Err(err) = parser.parse_expression() else {
+            panic!("expected a parse error");
+        };
+        assert_eq!(ParseErrorKind::UnexpectedEof, err.kind);
+        assert_eq!(vec!["')'".to_string()], err.expected);
+        assert_eq!(Token::Eof, err.found);
+        assert_eq!(2, err.span.start.line);
+
+        let source = "foo(1 2)";
+        let mut bufreader = BufReader::new(source.as_bytes());
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let Err(err) = parser.parse_expression() else {
+            panic!("expected a parse error");
+        };
+        assert_eq!(ParseErrorKind::UnexpectedToken, err.kind);
+        assert_eq!(num_tok(2.0), err.found);
+        assert_eq!(
+            "error: Expected ')' or ',' in argument list (expected ')' or ',', found number 2)\n \
+             --> 1:7\n  \
+             |\n\
+             1 | foo(1 2)\n  \
+             |       ^\n",
+            err.render(source));
     }
 
-    fn compare_ast_opts(ast_result_opt: Option<Box<ExprAST>>, ast_expected_opt: Option<Box<ExprAST>>) -> bool {
-        if let Some(ast_expected) = ast_expected_opt {
-            let Some(ast_result) = ast_result_opt else {
-                return false;
-            };
+    #[test]
+    pub fn test_parse_lists() {
+        let program = parse_source(b"def f(a, b,) a f(1, g(2,),) extern h() h()");
+        assert!(program.is_ok(), "unexpected parse errors: {:?}", program.errors);
+        let Item::Definition(function) = &program.items[0] else { panic!("expected a definition") };
+        assert_eq!(vec!["a", "b"], function.proto.args);
+        let Item::TopLevelExpr(function) = &program.items[1] else { panic!("expected an expression") };
+        assert_ast_eq(&call("f", vec![num(1.0), call("g", vec![num(2.0)])]), &function.body);
 
-            if let ExprAST::NumberExprAST { val: val_expected } = *ast_expected {
-                if let ExprAST::NumberExprAST { val: val_result } = *ast_result {
-                    return (val_expected - val_result).abs() < 1e-7;
-                } else {
-                    return false;
-                }
-            } else if let ExprAST::VariableExprAST { name: name_expected } = *ast_expected {
-                if let ExprAST::VariableExprAST { name: name_result } = *ast_result {
-                    return name_expected == name_result;
-                } else {
-                    return false;
-                }
-            } else if let ExprAST::BinaryExprAST { op: op_expected, lhs: lhs_expected, rhs: rhs_expected} = *ast_expected {
-                if let ExprAST::BinaryExprAST { op: op_result, lhs: lhs_result, rhs: rhs_result } = *ast_result {
-                    return op_expected == op_result
-                            && compare_ast_opts(Some(lhs_expected), Some(lhs_result))
-                            && compare_ast_opts(Some(rhs_expected), Some(rhs_result));
-                } else {
-                    return false;
-                }
-            } else if let ExprAST::PrototypeAST { name: name_expected, args: args_expected } = *ast_expected {
-                if let ExprAST::PrototypeAST { name: name_result, args: args_result } = *ast_result {
-                    return name_expected == name_result && args_expected == args_result;
-                } else {
-                    return false;
-                }
-            } else if let ExprAST::FunctionAST { proto: proto_expected, body: body_expected } = *ast_expected {
-                if let ExprAST::FunctionAST { proto: proto_result, body: body_result } = *ast_result {
-                    return compare_ast_opts(Some(proto_result), Some(proto_expected)) && compare_ast_opts(Some(body_result), Some(body_expected));
-                } else {
-                    return false;
-                }
-            } else if let ExprAST::CallExprAST { callee: callee_expected, args: args_expected} = *ast_expected {
-                if let ExprAST::CallExprAST { callee: callee_result, args: args_result } = *ast_result {
-                    if callee_expected != callee_result || args_expected.len() != args_result.len() {
-                        return false;
-                    }
-
-                    for (arg_expected, arg_result) in args_expected.into_iter().zip(args_result.into_iter()) {
-                        if !compare_ast_opts(Some(arg_expected), Some(arg_result)) {
-                            return false;
-                        }
-                    }
-
-                    return true;
-                } else {
-                    return false;
-                }
-            } else {
-                unreachable!();
-            }
-        } else {
-            return ast_expected_opt.is_none();
+        for source in ["def f(+ ,) 1", "def f(a b) 1", "def f(,) 1", "def f(a,,) 1", "f(,)", "f(1,,)", "f(1 2)"] {
+            let program = parse_source(source.as_bytes());
+            assert_eq!(1, program.errors.len(), "{source}");
+            assert_eq!(ParseErrorKind::UnexpectedToken, program.errors[0].kind, "{source}");
+        }
+
+        let program = parse_source(b"def f(a, b, a) 1");
+        assert_eq!(1, program.errors.len());
+        assert_eq!(ParseErrorKind::DuplicateParameter, program.errors[0].kind);
+        assert_eq!(13, program.errors[0].span.start.column);
+    }
+
+    #[test]
+    pub fn test_parse_program() {
+        let mut bufreader = BufReader::new(
+            r#"
+                extern sin(a)
+                def add(a, b) a + b
+                4 * 2
+            "#.as_bytes());
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let program = parser.parse();
+        assert!(program.is_ok());
+        assert_eq!(3, program.items.len());
+
+        let mut items = program.items.into_iter();
+        let Some(Item::Extern(proto)) = items.next() else {
+            panic!("expected an extern");
+        };
+        assert_eq!("sin", proto.name);
+        assert_eq!(vec![String::from("a")], proto.args);
+
+        let Some(Item::Definition(function)) = items.next() else {
+            panic!("expected a definition");
+        };
+        assert_eq!("add", function.proto.name);
+        assert_eq!(vec![String::from("a"), String::from("b")], function.proto.args);
+        assert_ast_eq(&bin("+", var("a"), var("b")), &function.body);
+
+        let Some(Item::TopLevelExpr(function)) = items.next() else {
+            panic!("expected a top-level expression");
+        };
+        assert_eq!(ANON_FUNCTION_NAME, function.proto.name);
+        assert!(function.proto.args.is_empty());
+        assert_ast_eq(&bin("*", num(4.0), num(2.0)), &function.body);
+    }
+
+    #[test]
+    pub fn test_parse_program_error() {
+        let mut bufreader = BufReader::new("def f(x) x\ndef (".as_bytes());
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let program = parser.parse();
+        assert_eq!(1, program.items.len());
+        assert_eq!(1, program.errors.len());
+        assert_eq!(2, program.errors[0].span.start.line);
+    }
+
+    #[test]
+    pub fn test_parse_recovery() {
+        let mut bufreader = BufReader::new(
+            r#"
+                def f(x) x +
+                def g(y) (y
+                extern h(
+                1 + 2
+                def ok(z) z
+            "#.as_bytes());
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let program = parser.parse();
+        let lines: Vec<usize> = program.errors.iter().map(|err| err.span.start.line).collect();
+        assert_eq!(vec![3, 4, 5], lines);
+        assert_eq!(1, program.items.len());
+    }
+
+    #[test]
+    pub fn test_parse_recovery_stray_token() {
+        let mut bufreader = BufReader::new("def 5 f(x) x def g(x) x".as_bytes());
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let program = parser.parse();
+        assert_eq!(1, program.errors.len());
+        assert_eq!(1, program.items.len());
+    }
+
+    #[test]
+    pub fn test_parse_recovery_stray_paren() {
+        let mut bufreader = BufReader::new(") def f(x) x".as_bytes());
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let program = parser.parse();
+        assert_eq!(1, program.errors.len());
+        assert_eq!(Token::RParen, program.errors[0].found);
+        assert_eq!(1, program.items.len());
+    }
+
+    #[test]
+    pub fn test_parse_binary_operator() {
+        let mut bufreader = BufReader::new("def binary| 5 (a, b) a\na | b < c\ndef binary& (a, b) b".as_bytes());
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let program = parser.parse();
+        assert!(program.is_ok(), "unexpected parse errors: {:?}", program.errors);
+
+        let Item::Definition(function) = &program.items[0] else { panic!("expected a definition") };
+        assert_eq!("binary|", function.proto.name);
+        assert_eq!(PrototypeKind::BinaryOp { precedence: 5 }, function.proto.kind);
+        let Item::TopLevelExpr(function) = &program.items[1] else { panic!("expected an expression") };
+        assert_ast_eq(&bin("|", var("a"), bin("<", var("b"), var("c"))), &function.body);
+        let Item::Definition(function) = &program.items[2] else { panic!("expected a definition") };
+        assert_eq!(PrototypeKind::BinaryOp { precedence: DEFAULT_OPERATOR_PRECEDENCE }, function.proto.kind);
+    }
+
+    #[test]
+    pub fn test_parse_binary_operator_errors() {
+        let sources = ["def binary+ (a, b) a", "def binary| 0 (a, b) a", "def binary| 2.5 (a, b) a",
+                       "def binary| 5 (a) a", "def binary| 5 (a, b, c) a"];
+        for source in sources {
+            let program = parse_source(source.as_bytes());
+            assert_eq!(1, program.errors.len(), "{source}");
+            assert_eq!(ParseErrorKind::InvalidOperator, program.errors[0].kind, "{source}");
+        }
+
+        // an operator that failed to parse is not registered, so `| 2` is
+        // a separate expression rather than the right operand of `x`
+        let program = parse_source("def binary| 5 (a) a\ndef f(x) x | 2".as_bytes());
+        assert_eq!(1, program.errors.len());
+        let Item::Definition(function) = &program.items[0] else { panic!("expected a definition") };
+        assert_ast_eq(&var("x"), &function.body);
+
+        // nor is one whose body failed to parse, and a redefinition that
+        // failed leaves the earlier precedence in place
+        let program = parse_source("def binary| 5 (a, b) )\ndef f(x) x | 2".as_bytes());
+        let Item::Definition(function) = &program.items[0] else { panic!("expected a definition") };
+        assert_ast_eq(&var("x"), &function.body);
+        let program = parse_source("def binary| 5 (a, b) a\ndef binary| 50 (a, b) );\n1 | 2 + 3".as_bytes());
+        assert_eq!(1, program.errors.len());
+        let Item::TopLevelExpr(function) = &program.items[1] else { panic!("expected an expression") };
+        assert_ast_eq(&bin("|", num(1.0), bin("+", num(2.0), num(3.0))), &function.body);
+    }
+
+    #[test]
+    pub fn test_ast_unary() {
+        test_input_ast("-a * b", Some(bin("*", unary("-", var("a")), var("b"))));
+        test_input_ast("- -x", Some(unary("-", unary("-", var("x")))));
+        test_input_ast("1 - -(2 + 3)", Some(bin("-", num(1.0), unary("-", bin("+", num(2.0), num(3.0))))));
+    }
+
+    #[test]
+    pub fn test_ast_comparison_and_logical() {
+        test_input_ast("a || b && c == d < e + f / g",
+            Some(logical("||", var("a"), logical("&&", var("b"),
+                bin("==", var("c"), bin("<", var("d"), bin("+", var("e"), bin("/", var("f"), var("g")))))))));
+        test_input_ast("a >= 1 && b != 2 || c % 3 > d",
+            Some(logical("||", logical("&&", bin(">=", var("a"), num(1.0)), bin("!=", var("b"), num(2.0))),
+                bin(">", bin("%", var("c"), num(3.0)), var("d")))));
+    }
+
+    #[test]
+    pub fn test_ast_associativity() {
+        test_input_ast("a ^ b ^ c", Some(bin("^", var("a"), bin("^", var("b"), var("c")))));
+        test_input_ast("a - b - c", Some(bin("-", bin("-", var("a"), var("b")), var("c"))));
+        test_input_ast("a * b ^ c ^ d * e",
+            Some(bin("*", bin("*", var("a"), bin("^", var("b"), bin("^", var("c"), var("d")))), var("e"))));
+        test_input_ast("a < b == c < d",
+            Some(bin("==", bin("<", var("a"), var("b")), bin("<", var("c"), var("d")))));
+        test_input_ast("(a < b) < c", Some(bin("<", bin("<", var("a"), var("b")), var("c"))));
+
+        for source in ["a < b < c", "a == b != c", "a <= b + 1 > c", "f(x) + (a >= b < c)"] {
+            let program = parse_source(source.as_bytes());
+            assert_eq!(1, program.errors.len(), "{source}");
+            assert_eq!(ParseErrorKind::NonAssociative, program.errors[0].kind, "{source}");
+        }
+    }
+
+    #[test]
+    pub fn test_parse_unary_operator() {
+        let program = parse_source("def unary! (v) if v then 0 else 1\n!a".as_bytes());
+        assert!(program.is_ok(), "unexpected parse errors: {:?}", program.errors);
+
+        let Item::Definition(function) = &program.items[0] else { panic!("expected a definition") };
+        assert_eq!("unary!", function.proto.name);
+        assert_eq!(PrototypeKind::UnaryOp, function.proto.kind);
+
+        for source in ["def unary- (v) v", "def unary! (a, b) a", "def unary! () 1"] {
+            let program = parse_source(source.as_bytes());
+            assert_eq!(1, program.errors.len(), "{source}");
+            assert_eq!(ParseErrorKind::InvalidOperator, program.errors[0].kind, "{source}");
+        }
+    }
+
+    #[test]
+    pub fn test_ast_if() {
+        let inp = "if x < 3 then 1 else f(x - 1, 2)";
+        let expected_ast = Box::new(ExprAST::IfExprAST {
+            cond: bin("<", var("x"), num(3.0)),
+            then: num(1.0),
+            else_: call("f", vec![bin("-", var("x"), num(1.0)), num(2.0)]),
+            span: Span::default(),
+        });
+        test_input_ast(inp, Some(expected_ast));
+    }
+
+    #[test]
+    pub fn test_ast_var_and_assign() {
+        test_input_ast("var a = 1, b in a = b = a + 1", Some(Box::new(ExprAST::VarExprAST {
+            vars: vec![binding("a", Some(num(1.0))), binding("b", None)],
+            body: assign("a", assign("b", bin("+", var("a"), num(1.0)))),
+            span: Span::default(),
+        })));
+        test_input_ast("x = y || z", Some(assign("x", logical("||", var("y"), var("z")))));
+        test_input_ast("(x = 1) + 2", Some(bin("+", assign("x", num(1.0)), num(2.0))));
+
+        for source in ["1 = 2", "f(x) = 2", "a + b = c", "-x = 1", "var in 1", "var x = 1 y"] {
+            let program = parse_source(source.as_bytes());
+            assert_eq!(1, program.errors.len(), "{source}");
+        }
+
+        let program = parse_source("def f(x) x + 1 = 2".as_bytes());
+        assert_eq!(ParseErrorKind::InvalidAssignment, program.errors[0].kind);
+        assert_eq!(10, program.errors[0].span.start.column);
+    }
+
+    #[test]
+    pub fn test_ast_block() {
+        test_input_ast("{ a; b = 1; }", Some(block(vec![var("a"), assign("b", num(1.0))])));
+        test_input_ast("{}", Some(block(vec![])));
+        test_input_ast("{ var x = 1 in x; y }", Some(block(vec![
+            Box::new(ExprAST::VarExprAST {
+                vars: vec![binding("x", Some(num(1.0)))],
+                body: var("x"),
+                span: Span::default(),
+            }),
+            var("y"),
+        ])));
+        // `var` without `in` covers the rest of the block
+        test_input_ast("{ f(); var x = 1, y; x + y; x }", Some(block(vec![
+            call("f", vec![]),
+            Box::new(ExprAST::VarExprAST {
+                vars: vec![binding("x", Some(num(1.0))), binding("y", None)],
+                body: block(vec![bin("+", var("x"), var("y")), var("x")]),
+                span: Span::default(),
+            }),
+        ])));
+
+        for source in ["{ 1 2 }", "{ a; ", "{ var x = 1 y }", "{ ; }"] {
+            let program = parse_source(source.as_bytes());
+            assert!(!program.is_ok(), "{source}");
+        }
+    }
+
+    #[test]
+    pub fn test_parse_semicolons() {
+        let program = parse_source(b"; 1; 2;; def f(x) x; f(1) 3");
+        assert!(program.is_ok(), "unexpected parse errors: {:?}", program.errors);
+        assert_eq!(5, program.items.len());
+
+        // a broken expression only takes the rest of its statement with it
+        let program = parse_source(b"1 +; 2\n3 ) 4; 5");
+        let positions: Vec<(usize, usize)> = program.errors.iter()
+            .map(|err| (err.span.start.line, err.span.start.column))
+            .collect();
+        assert_eq!(vec![(1, 4), (2, 3)], positions);
+        assert_eq!(3, program.items.len());
+
+        // inside a block, the rest of the block goes too
+        let program = parse_source(b"def f(x) { x +; 1 }\nf(1); { { 1 +; { 2 } }; 3 }; 4");
+        let positions: Vec<(usize, usize)> = program.errors.iter()
+            .map(|err| (err.span.start.line, err.span.start.column))
+            .collect();
+        assert_eq!(vec![(1, 15), (2, 14)], positions);
+        assert_eq!(2, program.items.len());
+    }
+
+    #[test]
+    pub fn test_ast_for() {
+        let inp = "for i = 1, i < n, 2 in f(i)";
+        let expected_ast = Box::new(ExprAST::ForExprAST {
+            var: String::from("i"),
+            var_span: Span::default(),
+            start: num(1.0),
+            end: bin("<", var("i"), var("n")),
+            step: Some(num(2.0)),
+            body: call("f", vec![var("i")]),
+            span: Span::default(),
+        });
+        test_input_ast(inp, Some(expected_ast));
+
+        let inp = "for i = 0, i < 10 in i";
+        let expected_ast = Box::new(ExprAST::ForExprAST {
+            var: String::from("i"),
+            var_span: Span::default(),
+            start: num(0.0),
+            end: bin("<", var("i"), num(10.0)),
+            step: None,
+            body: var("i"),
+            span: Span::default(),
+        });
+        test_input_ast(inp, Some(expected_ast));
+
+        test_input_ast("for i = 0 in i", None);
+    }
+
+    #[test]
+    pub fn test_parse_readme_example() {
+        let mut bufreader = BufReader::new(
+            r#"
+                # Compute the x'th fibonacci number.
+                def fib(x)
+                  if x < 3 then
+                    1
+                  else
+                    fib(x-1)+fib(x-2)
+
+                # This expression will compute the 40th number.
+                fib(40)
+            "#.as_bytes());
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let program = parser.parse();
+        assert!(program.is_ok());
+        assert_eq!(2, program.items.len());
+    }
+
+    #[test]
+    pub fn test_trace() {
+        let log = Rc::new(RefCell::new(TraceLog::default()));
+        let mut bufreader = BufReader::new("1 + x".as_bytes());
+        let mut lexer = Tokenizer::with_trace_sink(&mut bufreader, Box::new(log.clone()));
+        let mut parser = Parser::new(&mut lexer);
+        parser.set_trace_sink(Box::new(log.clone()));
+        parser.parse_expression().unwrap();
+
+        let events = &log.borrow().events;
+        assert_eq!(TraceEvent::CharRead { ch: '1', position: Position { offset: 0, line: 1, column: 1 } }, events[0]);
+        assert!(events.contains(&TraceEvent::Token {
+            token: id_tok("x"),
+            span: Span::new(Position { offset: 4, line: 1, column: 5 }, Position { offset: 5, line: 1, column: 6 }),
+        }));
+        assert!(events.contains(&TraceEvent::BinaryOperator { op: String::from("+"), precedence: 20 }));
+        let rules: Vec<&str> = events.iter().filter_map(|event| match event {
+            TraceEvent::EnterRule { rule, .. } => Some(*rule),
+            _ => None,
+        }).collect();
+        assert_eq!(vec!["expression", "unary", "primary", "numberexpr", "binoprhs", "unary", "primary", "identifierexpr"], rules);
+    }
+
+    #[test]
+    pub fn test_ast_spans_and_equality() {
+        let mut bufreader = BufReader::new("f(1, x)\n  + 2.5".as_bytes());
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let ast = parser.parse_expression().unwrap();
+        let pos = |offset, line, column| Position { offset, line, column };
+
+        assert_eq!(Span::new(pos(0, 1, 1), pos(15, 2, 8)), ast.span());
+        let ExprAST::BinaryExprAST { lhs, rhs, .. } = ast.as_ref() else {
+            panic!("expected a binary expression");
+        };
+        assert_eq!(Span::new(pos(0, 1, 1), pos(7, 1, 8)), lhs.span());
+        assert_eq!(Span::new(pos(12, 2, 5), pos(15, 2, 8)), rhs.span());
+
+        let expected = bin("+", call("f", vec![num(1.0), var("x")]), num(2.5));
+        assert!(ast.eq_ignore_spans(&expected));
+        assert_ne!(&expected, &ast);
+        assert_eq!(ast.clone(), ast);
+        assert!(!ast.eq_ignore_spans(&bin("+", call("f", vec![num(1.0), var("y")]), num(2.5))));
+        assert!(!ast.eq_ignore_spans(&bin("-", call("f", vec![num(1.0), var("x")]), num(2.5))));
+        assert!(!ast.eq_ignore_spans(&bin("+", call("f", vec![num(1.0)]), num(2.5))));
+        assert!(num(0.1 + 0.2).eq_ignore_spans(&num(0.3)));
+        assert!(!num(0.1).eq_ignore_spans(&num(0.1001)));
+        assert!(num(f64::NAN).eq_ignore_spans(&num(f64::NAN)));
+    }
+
+    fn test_input_ast(input: &str, expected_ast: Option<Box<ExprAST>>) {
+        let mut bufreader = BufReader::new(input.as_bytes());
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let ast_result = parser.parse_expression().ok();
+        match (&ast_result, &expected_ast) {
+            (Some(result), Some(expected)) => assert_ast_eq(expected, result),
+            (None, None) => {},
+            _ => panic!("expected {expected_ast:#?}\ngot {ast_result:#?}"),
         }
     }
 
+    pub fn assert_ast_eq(expected: &ExprAST, result: &ExprAST) {
+        assert!(result.eq_ignore_spans(expected), "expected {expected:#?}\ngot {result:#?}");
+    }
+
+    pub fn num(val: f64) -> Box<ExprAST> {
+        Box::new(ExprAST::NumberExprAST { val, span: Span::default() })
+    }
+
+    pub fn var(name: &str) -> Box<ExprAST> {
+        Box::new(ExprAST::VariableExprAST { name: String::from(name), span: Span::default() })
+    }
+
+    pub fn unary(op: &str, operand: Box<ExprAST>) -> Box<ExprAST> {
+        Box::new(ExprAST::UnaryExprAST { op: op.to_string(), operand, span: Span::default() })
+    }
+
+    pub fn logical(op: &str, lhs: Box<ExprAST>, rhs: Box<ExprAST>) -> Box<ExprAST> {
+        Box::new(ExprAST::LogicalExprAST { op: op.to_string(), lhs, rhs, span: Span::default() })
+    }
+
+    pub fn binding(name: &str, init: Option<Box<ExprAST>>) -> VarBinding {
+        VarBinding { name: String::from(name), init, span: Span::default() }
+    }
+
+    pub fn assign(name: &str, value: Box<ExprAST>) -> Box<ExprAST> {
+        Box::new(ExprAST::AssignExprAST { name: name.to_string(), value, span: Span::default() })
+    }
+
+    #[allow(clippy::vec_box)]
+    pub fn block(exprs: Vec<Box<ExprAST>>) -> Box<ExprAST> {
+        Box::new(ExprAST::BlockExprAST { exprs, span: Span::default() })
+    }
+
+    pub fn bin(op: &str, lhs: Box<ExprAST>, rhs: Box<ExprAST>) -> Box<ExprAST> {
+        Box::new(ExprAST::BinaryExprAST { op: String::from(op), lhs, rhs, span: Span::default() })
+    }
+
+    #[allow(clippy::vec_box)] // mirrors `CallExprAST::args`
+    pub fn call(callee: &str, args: Vec<Box<ExprAST>>) -> Box<ExprAST> {
+        Box::new(ExprAST::CallExprAST { callee: String::from(callee), args, span: Span::default() })
+    }
+
     pub fn id_tok(s: &str) -> Token {
         Token::Identifier { id: String::from(s) }
     }
 
+    pub fn op_tok(s: &str) -> Token {
+        Token::Operator { op: String::from(s) }
+    }
+
     pub fn num_tok(v: f64) -> Token {
         Token::Number { value: v }
     }
 }
+
+#[cfg(test)]
+mod test_eval {
+    use super::*;
+    use crate::eval::{self, Interpreter, RuntimeError, DEFAULT_MAX_CALL_DEPTH};
+
+    fn run(input: &str) -> Result<Vec<f64>, RuntimeError> {
+        let mut bufreader = BufReader::new(input.as_bytes());
+        let mut lexer = Tokenizer::new(&mut bufreader);
+        let mut parser = Parser::new(&mut lexer);
+        let program = parser.parse();
+        assert!(program.is_ok(), "unexpected parse errors: {:?}", program.errors);
+        Interpreter::new().run(program)
+    }
+
+    #[test]
+    pub fn test_eval_arithmetic() {
+        assert_eq!(Ok(vec![69.0]), run("5 + (6 + 7) * 8 - 40"));
+        assert_eq!(Ok(vec![1.0, 0.0]), run("1 < 2 2 < 1"));
+    }
+
+    #[test]
+    pub fn test_eval_fib() {
+        let source = r#"
+            def fib(x)
+              if x < 3 then
+                1
+              else
+                fib(x-1)+fib(x-2)
+
+            fib(20)
+        "#;
+        assert_eq!(Ok(vec![6765.0]), run(source));
+    }
+
+    #[test]
+    pub fn test_eval_extern_and_for() {
+        let source = r#"
+            extern sqrt(x)
+            def count(n) for i = 0, i < n in sqrt(i)
+            sqrt(16)
+            count(3)
+        "#;
+        assert_eq!(Ok(vec![4.0, 0.0]), run(source));
+    }
+
+    #[test]
+    pub fn test_eval_errors() {
+        assert_eq!(Err(RuntimeError::UnknownFunction(String::from("g"))), run("g(1)"));
+        assert_eq!(Err(RuntimeError::UnknownExtern(String::from("launch"))), run("extern launch()"));
+        assert_eq!(
+            Err(RuntimeError::ArityMismatch { name: String::from("f"), expected: 2, found: 1 }),
+            run("def f(a, b) a f(1)"));
+        assert_eq!(Err(RuntimeError::UnboundVariable(String::from("y"))), run("def f(x) y f(1)"));
+        assert_eq!(
+            Err(RuntimeError::UnboundVariable(String::from("i"))),
+            run("def f(n) (for i = 0, i < n in i) + i f(2)"));
+        assert_eq!(
+            Err(RuntimeError::StackOverflow { name: String::from("f"), limit: DEFAULT_MAX_CALL_DEPTH }),
+            run("def f(x) f(x + 1) f(0)"));
+    }
+
+    #[test]
+    pub fn test_eval_large_stack() {
+        let result = eval::with_large_stack(|max_call_depth| {
+            let program = parse_source("def f(n) if n < 1 then 0 else 1 + f(n - 1) f(5000)".as_bytes());
+            Interpreter::new().with_max_call_depth(max_call_depth).run(program)
+        });
+        assert_eq!(Ok(vec![5000.0]), result);
+    }
+
+    #[test]
+    pub fn test_eval_comparison_and_logical() {
+        assert_eq!(Ok(vec![1.0, 0.0, 1.0, 1.0, 0.0, 1.0]), run("2 > 1 2 <= 1 2 >= 2 3 == 3 3 != 3 0.5 != 1"));
+        assert_eq!(Ok(vec![2.5, 1.0, -1.0]), run("5 / 2 7 % 3 0 - 7 % 3"));
+        assert_eq!(Ok(vec![512.0, 18.0]), run("2 ^ 3 ^ 2 2 * 3 ^ 2"));
+        assert_eq!(Ok(vec![1.0, 0.0, 1.0, 0.0]), run("2 && 3 2 && 0 0 || 5 0 || 0"));
+        // the right operand is skipped once the left one decides the result
+        assert_eq!(Ok(vec![0.0, 1.0]), run("0 && undefined() 1 || undefined()"));
+        assert_eq!(Err(RuntimeError::UnknownFunction(String::from("undefined"))), run("1 && undefined()"));
+    }
+
+    #[test]
+    pub fn test_eval_var_and_assign() {
+        let source = r#"
+            def binary : 1 (x, y) y
+            def fib(n)
+              var a = 0, b = 1, t in
+                (for i = 0, i < n in
+                  t = a + b : a = b : b = t) : a
+            fib(10)
+            var x = 2, y = x * 3 in y
+            def inc(p) (p = p + 1) + p
+            inc(1)
+        "#;
+        assert_eq!(Ok(vec![55.0, 6.0, 4.0]), run(source));
+        // a binding is gone once its `var` expression is done
+        assert_eq!(
+            Err(RuntimeError::UnboundVariable(String::from("x"))),
+            run("def f() (var x = 1 in x) + x f()"));
+        assert_eq!(Err(RuntimeError::UnboundVariable(String::from("y"))), run("def f() y = 1 f()"));
+    }
+
+    #[test]
+    pub fn test_eval_block() {
+        let source = r#"
+            def f(x) {
+              var y = x * 2;
+              y = y + 1;
+              y
+            }
+            f(3);
+            {};
+            { 1; 2 }
+        "#;
+        assert_eq!(Ok(vec![7.0, 0.0, 2.0]), run(source));
+        assert_eq!(
+            Err(RuntimeError::UnboundVariable(String::from("z"))),
+            run("def g() { { var z = 1; z }; z } g()"));
+    }
+
+    #[test]
+    pub fn test_eval_unary_operator() {
+        let source = r#"
+            def unary! (v) if v then 0 else 1
+            !0 + !5
+            (-3) + 1
+            0 - -2
+        "#;
+        assert_eq!(Ok(vec![1.0, -2.0, 2.0]), run(source));
+        assert_eq!(Err(RuntimeError::UnknownOperator(String::from("~"))), run("~1"));
+    }
+
+    #[test]
+    pub fn test_eval_binary_operator() {
+        let source = r#"
+            def binary| 5 (a, b) if a then 1 else if b then 1 else 0
+            def binary : 1 (x, y) y
+            0 | 1
+            0 | 0
+            1 < 2 | 0
+            1 : 2 : 3
+        "#;
+        assert_eq!(Ok(vec![1.0, 0.0, 1.0, 3.0]), run(source));
+    }
+}
+
+#[cfg(test)]
+mod test_sema {
+    use crate::parser::parse_source;
+    use crate::sema::{analyze, has_errors, Analyzer, DiagnosticKind, Severity};
+
+    fn kinds(source: &str) -> Vec<DiagnosticKind> {
+        let program = parse_source(source.as_bytes());
+        assert!(program.is_ok(), "unexpected parse errors: {:?}", program.errors);
+        analyze(&program).into_iter().map(|diagnostic| diagnostic.kind).collect()
+    }
+
+    #[test]
+    pub fn test_sema_valid_program() {
+        let source = r#"
+            extern sqrt(x);
+            extern sqrt(y);
+            def unary! (v) if v then 0 else 1;
+            def binary : 1 (x, y) y;
+            def even(n) if n == 0 then 1 else odd(n - 1);
+            def odd(n) if n == 0 then 0 else even(n - 1);
+            def f(n) {
+              var total = 0;
+              for i = 0, i < n in total = total + sqrt(i) : !i;
+              total
+            }
+            f(3) + even(4)
+        "#;
+        assert_eq!(Vec::<DiagnosticKind>::new(), kinds(source));
+    }
+
+    #[test]
+    pub fn test_sema_errors() {
+        use DiagnosticKind::*;
+        assert_eq!(vec![UndefinedFunction], kinds("def f(x) g(x)"));
+        assert_eq!(vec![UndefinedVariable, UndefinedVariable], kinds("def f(x) y + (var z = 1 in z) + z"));
+        assert_eq!(vec![UndefinedVariable], kinds("def f(x) y = x"));
+        assert_eq!(vec![UndefinedOperator], kinds("def f(x) ~x"));
+        assert_eq!(vec![ArityMismatch, ArityMismatch], kinds("def f(a, b) a f(1) f(1, 2, 3) f(1, 2)"));
+        assert_eq!(vec![DuplicateDefinition], kinds("def f(x) x def f(y) y"));
+        assert_eq!(vec![ArityMismatch, DuplicateDefinition], kinds("extern sin(x) extern sin(x, y)"));
+        assert_eq!(vec![DuplicateDefinition], kinds("extern sin(x) def sin(x) x"));
+        assert_eq!(vec![UnknownExtern], kinds("extern launch(x)"));
+        assert_eq!(vec![ArityMismatch], kinds("extern pow(x)"));
+    }
+
+    #[test]
+    pub fn test_sema_call_before_definition() {
+        // all definitions are registered before any top-level expression runs
+        assert_eq!(Vec::<DiagnosticKind>::new(), kinds("f(1) def f(x) x"));
+        assert_eq!(Vec::<DiagnosticKind>::new(), kinds("def f(x) g(x) f(1) def g(x) x"));
+        let program = parse_source(b"def f(x) g(x) f(1) def g(x) x + 1");
+        assert_eq!(Ok(vec![2.0]), crate::eval::Interpreter::new().run(program));
+    }
+
+    #[test]
+    pub fn test_sema_shadowing() {
+        let source = "def f(x) (var x = 1 in x) + (for i = 0, i < 1 in for i = 0, i < 1 in i)";
+        let program = parse_source(source.as_bytes());
+        let diagnostics = analyze(&program);
+        assert_eq!(2, diagnostics.len());
+        assert!(diagnostics.iter().all(|diagnostic| diagnostic.severity() == Severity::Warning));
+        assert!(!has_errors(&diagnostics));
+        assert!(diagnostics[0].render(source).starts_with("warning: 'x' shadows an outer variable of the same name\n"));
+        // only the shadowing name is underlined
+        let columns: Vec<(usize, usize)> = diagnostics.iter()
+            .map(|diagnostic| (diagnostic.span.start.column, diagnostic.span.end.column))
+            .collect();
+        assert_eq!(vec![(15, 16), (54, 55)], columns);
+    }
+
+    #[test]
+    pub fn test_sema_location() {
+        let source = "def f(x) x\n1 + f(1, 2)";
+        let diagnostics = analyze(&parse_source(source.as_bytes()));
+        assert_eq!(
+            "error: Function 'f' takes 1 argument(s) but 2 were supplied\n \
+             --> 2:5\n  \
+             |\n\
+             2 | 1 + f(1, 2)\n  \
+             |     ^^^^^^^\n",
+            diagnostics[0].render(source));
+    }
+
+    #[test]
+    pub fn test_sema_analyzer_remembers_declarations() {
+        let mut analyzer = Analyzer::new();
+        assert!(analyzer.analyze(&parse_source(b"def f(x) x")).is_empty());
+        assert!(analyzer.analyze(&parse_source(b"f(1)")).is_empty());
+        // redefining at a later prompt is fine
+        assert!(analyzer.analyze(&parse_source(b"def f(x, y) x f(1, 2)")).is_empty());
+
+        // nothing from a program with errors is kept
+        assert!(has_errors(&analyzer.analyze(&parse_source(b"def g(x) x nope()"))));
+        assert!(has_errors(&analyzer.analyze(&parse_source(b"g(1)"))));
+    }
+}
+
+#[cfg(test)]
+mod test_opt {
+    use crate::eval::Interpreter;
+    use crate::opt::{fold, optimize};
+    use crate::parser::{parse_source, ExprAST, Item};
+
+    /// Optimizes the single top-level expression in `source`, returning it
+    /// printed along with the number of rewrites.
+    fn fold_source(source: &str) -> (String, usize) {
+        let mut program = parse_source(source.as_bytes());
+        assert!(program.is_ok(), "unexpected parse errors: {:?}", program.errors);
+        let rewrites = optimize(&mut program);
+        match program.items.last() {
+            Some(Item::TopLevelExpr(function)) => (function.body.to_string(), rewrites.len()),
+            item => panic!("expected a top-level expression, found {item:?}"),
+        }
+    }
+
+    #[test]
+    pub fn test_opt_constants() {
+        let mut rewrites = Vec::new();
+        let program = parse_source(b"5 + (6 + 7) * 8");
+        let Some(Item::TopLevelExpr(function)) = program.items.into_iter().next() else { unreachable!() };
+        let folded = fold(*function.body, &mut rewrites);
+        assert!(matches!(folded, ExprAST::NumberExprAST { val, .. } if val == 109.0));
+        let steps: Vec<String> = rewrites.iter().map(|rewrite| rewrite.to_string()).collect();
+        assert_eq!(vec!["1:6\t6 + 7 => 13", "1:6\t13 * 8 => 104", "1:1\t5 + 104 => 109"], steps);
+
+        assert_eq!(("1".to_string(), 2), fold_source("2 ^ 2 > 3"));
+        assert_eq!(("2".to_string(), 2), fold_source("-(1 - 3)"));
+        assert_eq!(("x + 3".to_string(), 1), fold_source("x + (1 + 2)"));
+        // evaluation order is left to right, so `x + 1 + 2` has nothing constant
+        assert_eq!(("x + 1 + 2".to_string(), 0), fold_source("x + 1 + 2"));
+    }
+
+    #[test]
+    pub fn test_opt_identities() {
+        assert_eq!(("x".to_string(), 1), fold_source("x * 1"));
+        assert_eq!(("f(x)".to_string(), 1), fold_source("extern f(x) 1 * f(x)"));
+        assert_eq!(("x".to_string(), 2), fold_source("x / 1 ^ 1"));
+        assert_eq!(("x".to_string(), 1), fold_source("x - 0"));
+        assert_eq!(("x".to_string(), 2), fold_source("x + -0"));
+        assert_eq!(("x".to_string(), 2), fold_source("-0 + x"));
+        // these would change the result for some `x`
+        for source in ["x + 0", "0 + x", "x - -0", "x * 0", "0 * x", "1 / x", "x ^ 1", "0 - x"] {
+            let (folded, _) = fold_source(source);
+            assert_eq!(parse_source(source.as_bytes()).items[0].to_string(), folded, "{source}");
+        }
+    }
+
+    #[test]
+    pub fn test_opt_control_flow() {
+        assert_eq!(("f(x)".to_string(), 2), fold_source("extern f(x) extern g(x) if 1 < 2 then f(x) else g(x)"));
+        assert_eq!(("g(x)".to_string(), 1), fold_source("extern f(x) extern g(x) if 0 then f(x) else g(x)"));
+        assert_eq!(("0".to_string(), 1), fold_source("extern f(x) 0 && f(x)"));
+        assert_eq!(("1".to_string(), 1), fold_source("extern f(x) 2 || f(x)"));
+        assert_eq!(("1".to_string(), 2), fold_source("1 && 0 / 0"));
+        // the call still has to happen
+        assert_eq!(("f(x) && 0".to_string(), 0), fold_source("extern f(x) f(x) && 0"));
+        assert_eq!(("1 && f(x)".to_string(), 0), fold_source("extern f(x) 1 && f(x)"));
+        assert_eq!(("for i = 0, i < 3 in f(i)".to_string(), 1), fold_source("extern f(x) for i = 0, i < 1 + 2 in f(i)"));
+        // user-defined operators can be redefined, so they are left alone
+        assert_eq!(("1 | 2".to_string(), 0), fold_source("def binary | 5 (a, b) a 1 | 2"));
+    }
+
+    #[test]
+    pub fn test_opt_preserves_values() {
+        let exprs = [
+            "x * 1", "1 * x", "x / 1", "x ^ 1", "x - 0", "x + -0", "-0 + x", "x + 0", "x * 0",
+            "if x then 1 else 2", "x && 1", "0 || x", "-x - -0", "{ x; 2 * 3 }",
+        ];
+        let values = ["0", "-0", "2.5", "-2.5", "1 / 0", "-1 / 0", "0 / 0"];
+        for expr in exprs {
+            let source = format!("def f(x) {expr}\n{}", values.map(|value| format!("f({value})")).join("\n"));
+            let program = parse_source(source.as_bytes());
+            let mut optimized = program.clone();
+            optimize(&mut optimized);
+            let expected = Interpreter::new().run(program).unwrap();
+            let actual = Interpreter::new().run(optimized).unwrap();
+            let bits = |values: Vec<f64>| values.into_iter().map(f64::to_bits).collect::<Vec<_>>();
+            assert_eq!(bits(expected), bits(actual), "{expr}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_repl {
+    use crate::repl::Repl;
+
+    fn run_repl(input: &str) -> String {
+        let mut output = Vec::new();
+        Repl::new(&mut output).run(input.as_bytes()).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    pub fn test_repl_session() {
+        let output = run_repl("def add(a, b)\n  a +\n  b\nadd(1, 2)\n\n:ast\n(1+2) * x\n:bogus\n:quit\n5\n");
+        let expected = "ready> ...> ...> ready> 3\n\
+                        ready> ready> AST printing on\n\
+                        ready> (1 + 2) * x;\n\
+                        error: Unknown variable 'x'\n";
+        assert!(output.starts_with(expected), "unexpected output:\n{output}");
+        assert!(output.ends_with("ready> error: unknown command ':bogus', try :help\nready> "));
+
+        // the AST is printed as parsed, before constant folding
+        let output = run_repl(":ast\ndef binary| 5 (a, b) a + b\n(1 + 2) * (3 | 4)\n");
+        assert!(output.ends_with("ready> (1 + 2) * (3 | 4);\n21\nready> \n"), "unexpected output:\n{output}");
+    }
+
+    #[test]
+    pub fn test_repl_errors() {
+        let output = run_repl("1 + )\nfoo(2)\n");
+        assert!(output.contains("error: Expected expression (expected expression, found ')')\n"));
+        assert!(output.contains("1 | 1 + )\n  |     ^\n"));
+        assert!(output.contains("error: Unknown function 'foo'\n"));
+    }
+
+    #[test]
+    pub fn test_repl_binary_operator() {
+        let output = run_repl("def binary~ 40 (a, b)\n  a - b * 2\n1 + 9 ~ 2\n");
+        assert_eq!("ready> ...> ready> 6\nready> \n", output);
+    }
+
+    #[test]
+    pub fn test_repl_rejected_operator() {
+        // the rejected redefinition doesn't change the precedence of `|`
+        let output = run_repl("def binary| 5 (a, b) a + b\ndef binary| 50 (a, b) nope(a)\n1 | 2 * 3\n");
+        assert!(output.contains("error: Unknown function 'nope'\n"), "unexpected output:\n{output}");
+        assert!(output.ends_with("ready> 7\nready> \n"), "unexpected output:\n{output}");
+
+        let path = std::env::temp_dir().join(format!("kaleidoscope-repl-op-{}.ks", std::process::id()));
+        std::fs::write(&path, "def binary| 5 (a, b) nope(a)\n").unwrap();
+        let output = run_repl(&format!(":load {}\ndef binary| 5 (a, b) a + b\n1 | 2\n", path.display()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(output.ends_with("ready> ready> 3\nready> \n"), "unexpected output:\n{output}");
+    }
+
+    #[test]
+    pub fn test_repl_semicolons() {
+        let output = run_repl("1; 2\ndef f(x) {\n  x;\n  x + 1 }; f(1)\n");
+        assert_eq!("ready> 1\n2\nready> ...> ...> 2\nready> \n", output);
+    }
+
+    #[test]
+    pub fn test_repl_commands_while_pending() {
+        let output = run_repl("def f(x)\n:ast\nx + 1\nf(1\n:cancel\nf(2)\n");
+        assert_eq!("ready> ...> AST printing on\n...> def f(x) x + 1;\nready> ...> ready> f(2);\n3\nready> \n", output);
+
+        let output = run_repl("1 +\n:quit\n2\n");
+        assert_eq!("ready> ...> ", output);
+    }
+
+    #[test]
+    pub fn test_repl_load() {
+        let path = std::env::temp_dir().join(format!("kaleidoscope-repl-{}.ks", std::process::id()));
+        std::fs::write(&path, "def sq(x) x * x\nsq(3)\n").unwrap();
+        let output = run_repl(&format!(":load {}\nsq(4)\n", path.display()));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!("ready> 9\nready> 16\nready> \n", output);
+
+        let output = run_repl(":load /nonexistent/file.ks\n");
+        assert!(output.contains("error: cannot read '/nonexistent/file.ks'"));
+    }
+}
+
+#[cfg(test)]
+mod test_cli {
+    use crate::cli;
+
+    fn run_cli(args: &[&str], stdin: &str) -> (i32, String, String) {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let status = cli::run(&args, stdin.as_bytes(), &mut stdout, &mut stderr);
+        (status, String::from_utf8(stdout).unwrap(), String::from_utf8(stderr).unwrap())
+    }
+
+    #[test]
+    pub fn test_cli_lex() {
+        let (status, stdout, stderr) = run_cli(&["lex", "-"], "def f\n  1.2.3");
+        assert_eq!(cli::EXIT_LEXICAL, status);
+        assert_eq!("1:1\tDef\n1:5\tIdentifier { id: \"f\" }\n\
+                    2:3\tError { error: MalformedNumber(\"1.2.3\") }\n2:8\tEof\n", stdout);
+        assert!(stderr.starts_with("error: Malformed number literal '1.2.3'\n"));
+    }
+
+    #[test]
+    pub fn test_cli_parse() {
+        let (status, stdout, stderr) = run_cli(&["parse", "-"], "extern sin(x)");
+        assert_eq!(cli::EXIT_SUCCESS, status);
+        assert!(stdout.starts_with("Extern(\n    PrototypeAST {\n        name: \"sin\",\n"));
+        assert!(stderr.is_empty());
+
+        let (status, _, stderr) = run_cli(&["parse", "-"], "def f(x) (x");
+        assert_eq!(cli::EXIT_SYNTAX, status);
+        assert!(stderr.starts_with("error: Expected ')'"));
+    }
+
+    #[test]
+    pub fn test_cli_run() {
+        let (status, stdout, _) = run_cli(&["run", "-"], "def sq(x) x * x\nsq(3)\nsq(4)");
+        assert_eq!(cli::EXIT_SUCCESS, status);
+        assert_eq!("9\n16\n", stdout);
+
+        let (status, stdout, _) = run_cli(&["run", "-"], "def f(x) g(x)\nf(1)\ndef g(x) x");
+        assert_eq!(cli::EXIT_SUCCESS, status);
+        assert_eq!("1\n", stdout);
+
+        let (status, stdout, stderr) = run_cli(&["run", "-"], "1\nextern launch()\n2");
+        assert_eq!(cli::EXIT_SEMANTIC, status);
+        assert_eq!("", stdout);
+        assert!(stderr.starts_with("error: No native implementation for extern 'launch'\n"), "{stderr}");
+
+        // deep recursion runs on the large evaluation stack
+        let source = "def sum(n) if n == 0 then 0 else n + sum(n - 1)\nsum(5000)\nsum(20000)";
+        let (status, stdout, stderr) = run_cli(&["run", "-"], source);
+        assert_eq!(cli::EXIT_RUNTIME, status);
+        assert_eq!("12502500\n", stdout);
+        assert_eq!("error: Maximum call depth of 10000 exceeded in 'sum'\n", stderr);
+
+        // semantic errors are reported before anything runs
+        let (status, stdout, stderr) = run_cli(&["run", "-"], "1\nnope(1)\n2");
+        assert_eq!(cli::EXIT_SEMANTIC, status);
+        assert_eq!("", stdout);
+        assert!(stderr.starts_with("error: Unknown function 'nope'\n --> 2:1\n"), "{stderr}");
+    }
+
+    #[test]
+    pub fn test_cli_opt() {
+        let (status, stdout, stderr) = run_cli(&["opt", "-"], "def f(x) x * (2 - 1)\nf(3) + 0");
+        assert_eq!(cli::EXIT_SUCCESS, status);
+        assert_eq!("# 1:15\t2 - 1 => 1\n# 1:10\tx * 1 => x\ndef f(x) x;\nf(3) + 0;\n", stdout);
+        assert!(stderr.is_empty());
+
+        let (status, _, _) = run_cli(&["opt", "-"], "1 +");
+        assert_eq!(cli::EXIT_SYNTAX, status);
+    }
+
+    #[test]
+    pub fn test_cli_opt_output_runs() {
+        let source = "def binary| 5 (a, b) a + b\n\
+                      def binary~ 50 (a, b) a - b\n\
+                      def f(x) (x | 1) * 2 * 1 + (x + 1 ~ 2 * 1)\n\
+                      f(3);\n\
+                      1 / 0 + f(1e999);\n\
+                      -(1 / 0) * 2;\n\
+                      (0 / 0) + 1;\n\
+                      -(2 + 1) ^ 2";
+        let (status, optimized, _) = run_cli(&["opt", "-"], source);
+        assert_eq!(cli::EXIT_SUCCESS, status);
+        assert!(optimized.contains("\ndef f(x) (x | 1) * 2 + (x + 1 ~ 2);\n"), "{optimized}");
+        assert!(optimized.contains("\n(1 / 0) + f((1 / 0));\n(-1 / 0);\n(0 / 0);\n"), "{optimized}");
+
+        let (status, expected, _) = run_cli(&["run", "-"], source);
+        assert_eq!(cli::EXIT_SUCCESS, status);
+        let (status, actual, stderr) = run_cli(&["run", "-"], &optimized);
+        assert_eq!(cli::EXIT_SUCCESS, status, "{stderr}");
+        assert_eq!(expected, actual);
+        assert_eq!("10\ninf\n-inf\nNaN\n9\n", actual);
+    }
+
+    #[test]
+    pub fn test_cli_opt_output_keeps_items_apart() {
+        for source in ["def f(x) x; (f(1) + 2) * 3", "def f(x) x;\n-f(1);"] {
+            let (status, optimized, _) = run_cli(&["opt", "-"], source);
+            assert_eq!(cli::EXIT_SUCCESS, status);
+            let (_, expected, _) = run_cli(&["run", "-"], source);
+            let (status, actual, stderr) = run_cli(&["run", "-"], &optimized);
+            assert_eq!(cli::EXIT_SUCCESS, status, "{stderr}");
+            assert_eq!(expected, actual, "{optimized}");
+            assert_eq!(optimized, run_cli(&["opt", "-"], &optimized).1);
+        }
+    }
+
+    #[test]
+    pub fn test_cli_usage() {
+        assert_eq!(cli::EXIT_USAGE, run_cli(&["frobnicate"], "").0);
+        assert_eq!(cli::EXIT_USAGE, run_cli(&["run"], "").0);
+        assert_eq!(cli::EXIT_IO, run_cli(&["run", "/nonexistent/file.ks"], "").0);
+        let (status, stdout, _) = run_cli(&["--help"], "");
+        assert_eq!(cli::EXIT_SUCCESS, status);
+        assert!(stdout.starts_with("usage: kaleidoscope"));
+    }
+}
+
+#[cfg(test)]
+mod test_printer {
+    use super::*;
+    use super::test_frontend::{assert_ast_eq, assign, bin, binding, block, call, logical, num, unary, var};
+    use crate::lexer::Span;
+    use crate::parser::{
+        default_operators, parse_source, parse_source_with_operators, Associativity, Item, OperatorInfo,
+        OperatorTable,
+    };
+
+    /// Parses `input`, which must consist of exactly one expression.
+    fn parse_expr(input: &str) -> Box<ExprAST> {
+        parse_expr_with(input, &default_operators())
+    }
+
+    /// Like `parse_expr`, with the operators in `prec`.
+    fn parse_expr_with(input: &str, prec: &OperatorTable) -> Box<ExprAST> {
+        let program = parse_source_with_operators(input.as_bytes(), &mut prec.clone());
+        assert!(program.is_ok(), "cannot parse {input:?}: {:?}", program.errors);
+        match <[Item; 1]>::try_from(program.items) {
+            Ok([Item::TopLevelExpr(function)]) => function.body,
+            items => panic!("expected a single expression in {input:?}, got {items:?}"),
+        }
+    }
+
+    #[test]
+    pub fn test_print_minimal_parens() {
+        let cases = [
+            ("5 + (6 + 7) * 8", "5 + (6 + 7) * 8"),
+            ("(1 - 2) - 3", "1 - 2 - 3"),
+            ("1 - (2 - 3)", "1 - (2 - 3)"),
+            ("(a * b) + (c * d)", "a * b + c * d"),
+            ("((a < b))", "a < b"),
+            ("a < (b < c)", "a < (b < c)"),
+            ("f((1 + 2), g(), x)", "f(1 + 2, g(), x)"),
+            ("(if a then b else c) + 1", "(if a then b else c) + 1"),
+            ("1 + (for i = 0, i < 3 in i)", "1 + (for i = 0, i < 3 in i)"),
+            ("if a < 1 then f(a) else a * 2.5", "if a < 1 then f(a) else a * 2.5"),
+            ("for i = 0, i < n, 0.5 in x", "for i = 0, i < n, 0.5 in x"),
+            ("(-a) * -f(b)", "-a * -f(b)"),
+            ("-(a * b)", "-(a * b)"),
+            ("!-x", "!(-x)"),
+            (".(5)", ".(5)"),
+            (". x", ".x"),
+            ("(a || b) || (c && d)", "a || b || c && d"),
+            ("a && (b || c)", "a && (b || c)"),
+            ("(a == b) != (c < d / e)", "(a == b) != c < d / e"),
+            ("(a ^ b) ^ c", "(a ^ b) ^ c"),
+            ("a ^ (b ^ c)", "a ^ b ^ c"),
+            ("-a ^ 2 * b", "-a ^ 2 * b"),
+            ("x = (y = a || b)", "x = y = a || b"),
+            ("(x = 1) * 2 + (var a = 1, b in a)", "(x = 1) * 2 + (var a = 1, b in a)"),
+            ("{ a; (b = 1); }", "{ a; b = 1 }"),
+            ("-{ x } * {}", "-{ x } * {}"),
+            ("{ var x = 1; x }", "{ var x = 1 in { x } }"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(expected, parse_expr(input).to_string());
+        }
+    }
+
+    /// `|` at precedence 5 and `~` at 50, as if defined with `binary`.
+    fn user_operators() -> OperatorTable {
+        let mut prec = default_operators();
+        prec.insert(String::from("|"), OperatorInfo::new(5, Associativity::Left));
+        prec.insert(String::from("~"), OperatorInfo::new(50, Associativity::Left));
+        prec
+    }
+
+    #[test]
+    pub fn test_print_user_operators() {
+        let prec = user_operators();
+        let cases = [
+            // input, printed without knowing the operators, printed with them
+            ("(x | 1) * 2 * 1", "(x | 1) * 2 * 1", "(x | 1) * 2 * 1"),
+            ("x | 1 * 2", "x | (1 * 2)", "x | 1 * 2"),
+            ("(x + 1) ~ 2", "(x + 1) ~ 2", "(x + 1) ~ 2"),
+            ("x + 1 ~ 2", "x + (1 ~ 2)", "x + 1 ~ 2"),
+            ("a | b | c ~ d", "(a | b) | (c ~ d)", "a | b | c ~ d"),
+            ("!(a ~ b) ~ !c", "!(a ~ b) ~ !c", "!(a ~ b) ~ !c"),
+        ];
+        for (input, plain, with_prec) in cases {
+            let expr = parse_expr_with(input, &prec);
+            assert_eq!(plain, expr.to_string());
+            assert_eq!(with_prec, expr.display_with(&prec).to_string());
+        }
+    }
+
+    #[test]
+    pub fn test_print_non_finite_numbers() {
+        assert_eq!("(1 / 0)", parse_expr("1e999").to_string());
+        assert_eq!("-(1 / 0) * 2", parse_expr("-1e999 * 2").to_string());
+        assert_eq!("(-1 / 0)", num(f64::NEG_INFINITY).to_string());
+        assert_eq!("(0 / 0) + 1", bin("+", num(f64::NAN), num(1.0)).to_string());
+    }
+
+    #[test]
+    pub fn test_print_items() {
+        let program = parse_source(b"extern sin(x)\ndef f(a, b) a * (b + 1)\nf(1, 2)");
+        let printed: Vec<String> = program.items.iter().map(|item| item.to_string()).collect();
+        assert_eq!(vec!["extern sin(x)", "def f(a, b) a * (b + 1)", "f(1, 2)"], printed);
+
+        let program = parse_source(b"def binary| 5 (a, b) a def unary! (v) v");
+        let printed: Vec<String> = program.items.iter().map(|item| item.to_string()).collect();
+        assert_eq!(vec!["def binary| 5 (a, b) a", "def unary! (v) v"], printed);
+    }
+
+    /// Small deterministic xorshift generator so the property test needs no dependencies.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> usize {
+            (self.next() % n) as usize
+        }
+    }
+
+    fn random_expr(rng: &mut Rng, depth: u32) -> Box<ExprAST> {
+        const NUMBERS: [f64; 6] = [0.0, 1.0, 2.5, 42.0, 0.001, 123456.789];
+        const NAMES: [&str; 5] = ["x", "y", "abc", "n1", "_tmp"];
+        const OPS: [&str; 12] = ["<", ">", "<=", ">=", "==", "!=", "+", "-", "*", "/", "%", "^"];
+        const LOGICAL_OPS: [&str; 2] = ["&&", "||"];
+        const USER_OPS: [&str; 2] = ["|", "~"];
+        // `.` checks that a number operand doesn't lex as part of the operator
+        const UNARY_OPS: [&str; 2] = ["!", "."];
+
+        let leaf = depth == 0 || rng.below(4) == 0;
+        if leaf {
+            return if rng.below(2) == 0 {
+                num(NUMBERS[rng.below(NUMBERS.len() as u64)])
+            } else {
+                var(NAMES[rng.below(NAMES.len() as u64)])
+            };
+        }
+
+        match rng.below(15) {
+            0 => {
+                let args = (0..rng.below(4)).map(|_| random_expr(rng, depth - 1)).collect();
+                call(NAMES[rng.below(NAMES.len() as u64)], args)
+            },
+            1 => Box::new(ExprAST::IfExprAST {
+                cond: random_expr(rng, depth - 1),
+                then: random_expr(rng, depth - 1),
+                else_: random_expr(rng, depth - 1),
+                span: Span::default(),
+            }),
+            2 => Box::new(ExprAST::ForExprAST {
+                var: String::from(NAMES[rng.below(NAMES.len() as u64)]),
+                var_span: Span::default(),
+                start: random_expr(rng, depth - 1),
+                end: random_expr(rng, depth - 1),
+                step: if rng.below(2) == 0 { Some(random_expr(rng, depth - 1)) } else { None },
+                body: random_expr(rng, depth - 1),
+                span: Span::default(),
+            }),
+            3 => unary("-", random_expr(rng, depth - 1)),
+            5 => assign(NAMES[rng.below(NAMES.len() as u64)], random_expr(rng, depth - 1)),
+            6 => Box::new(ExprAST::VarExprAST {
+                vars: (0..=rng.below(2)).map(|_| {
+                    let init = if rng.below(2) == 0 { Some(random_expr(rng, depth - 1)) } else { None };
+                    binding(NAMES[rng.below(NAMES.len() as u64)], init)
+                }).collect(),
+                body: random_expr(rng, depth - 1),
+                span: Span::default(),
+            }),
+            7 => block((0..rng.below(4)).map(|_| random_expr(rng, depth - 1)).collect()),
+            8 => unary(UNARY_OPS[rng.below(2)], random_expr(rng, depth - 1)),
+            9 => bin(USER_OPS[rng.below(2)], random_expr(rng, depth - 1), random_expr(rng, depth - 1)),
+            4 => logical(LOGICAL_OPS[rng.below(2)], random_expr(rng, depth - 1), random_expr(rng, depth - 1)),
+            _ => bin(OPS[rng.below(OPS.len() as u64)], random_expr(rng, depth - 1), random_expr(rng, depth - 1)),
+        }
+    }
+
+    #[test]
+    pub fn test_print_round_trip() {
+        let prec = user_operators();
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..2000 {
+            let expr = random_expr(&mut rng, 5);
+            // without the table, user-defined operators are fully parenthesized
+            assert_ast_eq(&expr, &parse_expr_with(&expr.to_string(), &prec));
+            assert_ast_eq(&expr, &parse_expr_with(&expr.display_with(&prec).to_string(), &prec));
+        }
+    }
+}
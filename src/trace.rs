@@ -0,0 +1,52 @@
+use crate::lexer::{Position, Span, Token};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Something the tokenizer or parser did, reported to a `TraceSink`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    /// The tokenizer decoded a character starting at `position`.
+    CharRead { ch: char, position: Position },
+    /// The tokenizer produced a token.
+    Token { token: Token, span: Span },
+    /// The parser started on a grammar rule at the current token.
+    EnterRule { rule: &'static str, token: Token, span: Span },
+    /// The parser found a binary operator while parsing an expression.
+    BinaryOperator { op: String, precedence: i32 },
+    /// The parser skipped `skipped` tokens to recover from an error.
+    Synchronized { skipped: usize, span: Span },
+}
+
+/// Observer for lexer and parser events. Tracing is off unless a sink is
+/// installed with `Tokenizer::with_trace_sink` or `Parser::set_trace_sink`.
+pub trait TraceSink {
+    fn event(&mut self, event: &TraceEvent);
+}
+
+/// Prints every event to stderr, one per line.
+pub struct StderrTrace;
+
+impl TraceSink for StderrTrace {
+    fn event(&mut self, event: &TraceEvent) {
+        eprintln!("{event:?}");
+    }
+}
+
+/// Keeps every event in memory.
+#[derive(Debug, Default)]
+pub struct TraceLog {
+    pub events: Vec<TraceEvent>,
+}
+
+impl TraceSink for TraceLog {
+    fn event(&mut self, event: &TraceEvent) {
+        self.events.push(event.clone());
+    }
+}
+
+/// Lets the tokenizer and the parser report to the same sink.
+impl<S: TraceSink> TraceSink for Rc<RefCell<S>> {
+    fn event(&mut self, event: &TraceEvent) {
+        self.borrow_mut().event(event);
+    }
+}